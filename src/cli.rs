@@ -0,0 +1,374 @@
+use std::path::PathBuf;
+
+use librender::{
+    math::vector::Vec3f32,
+    scene::file::{Shading, Shadows},
+    utils,
+    video::y4m::ChromaSubsampling,
+};
+
+pub const USAGE: &str = "\
+usage: render-bin [options] [input]
+
+Renders a scene file, or an OBJ model with the textures next to it, to an
+image. The input defaults to scenes/diablo3.scene. Options override the
+settings of the scene file. Animations are written as numbered images,
+<stem>_0000.<extension> onwards, or as the frames of a video.
+
+options:
+  -o, --output <path>              output image, result.tga by default
+  -f, --format <format>            output format: tga, png, bmp, ppm, pam,
+                                   y4m or gif; guessed from the output
+                                   extension if not given
+  -r, --resolution <w>x<h>         output resolution
+      --shadow-resolution <w>x<h>  shadow map resolution
+  -c, --camera <node>              camera node of the scene
+      --eye <x>,<y>,<z>            camera position
+      --center <x>,<y>,<z>         point the camera looks at
+      --up <x>,<y>,<z>             camera up direction
+      --focal <length>             pinhole camera of the given focal length
+      --fov <degrees>              perspective camera of the given vertical
+                                   field of view
+      --shadows <mode>             map, volumes or none
+      --ao <on|off>                screen-space ambient occlusion
+      --aa <on|off>                4x multisample antialiasing
+  -s, --shading <mode>             textured, smooth, flat or normals
+      --turntable <frames>         render frames orbiting the camera once
+                                   around the up axis through the center,
+                                   the origin by default
+      --frames <count>             frame count of the animation
+      --fps <rate>                 frames per second of videos, as a number
+                                   or a fraction like 30000/1001; 25 by
+                                   default
+      --chroma <subsampling>       chroma subsampling of y4m videos: 420
+                                   (default), 422, 444 or mono
+      --dither <on|off>            dithering of gif frames to their palette,
+                                   on by default
+      --loop <count>               how many times gifs play, 0 (default) for
+                                   forever
+  -h, --help                       print this help
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tga,
+    Png,
+    Bmp,
+    Ppm,
+    Pam,
+    Y4m,
+    Gif,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "tga" => Some(Format::Tga),
+            "png" => Some(Format::Png),
+            "bmp" => Some(Format::Bmp),
+            "ppm" => Some(Format::Ppm),
+            "pam" => Some(Format::Pam),
+            "y4m" => Some(Format::Y4m),
+            "gif" => Some(Format::Gif),
+            _ => None,
+        }
+    }
+
+    /// Whether all frames go to one file, rather than an image each.
+    pub fn is_video(self) -> bool {
+        matches!(self, Format::Y4m | Format::Gif)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub help: bool,
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: Option<Format>,
+    pub resolution: Option<(u32, u32)>,
+    pub shadow_resolution: Option<(u32, u32)>,
+    pub camera: Option<String>,
+    pub eye: Option<Vec3f32>,
+    pub center: Option<Vec3f32>,
+    pub up: Option<Vec3f32>,
+    pub focal_length: Option<f32>,
+    pub fov_y: Option<f32>,
+    pub shadows: Option<Shadows>,
+    pub ambient_occlusion: Option<bool>,
+    pub antialiasing: Option<bool>,
+    pub shading: Option<Shading>,
+    pub turntable: Option<usize>,
+    pub frames: Option<usize>,
+    pub frame_rate: Option<(u32, u32)>,
+    pub chroma: Option<ChromaSubsampling>,
+    pub dithering: Option<bool>,
+    pub loop_count: Option<u16>,
+}
+
+impl Options {
+    /// Parses the command line arguments, without the program name.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> utils::Result<Options> {
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                if options.input.is_some() {
+                    return Err(format!("unexpected argument {}", arg).into());
+                }
+                options.input = Some(arg.into());
+                continue;
+            }
+
+            // both `--option value` and `--option=value` are accepted
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if name == "-h" || name == "--help" {
+                options.help = true;
+                continue;
+            }
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(format!("{} needs a value", name).into()),
+            };
+            options
+                .set(&name, &value)
+                .map_err(|e| format!("{}: {}", name, e))?;
+        }
+
+        if options.focal_length.is_some() && options.fov_y.is_some() {
+            return Err("--focal and --fov cannot be used together".into());
+        }
+        Ok(options)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> utils::Result<()> {
+        match name {
+            "-o" | "--output" => self.output = Some(value.into()),
+            "-f" | "--format" => {
+                self.format =
+                    Some(Format::from_name(value).ok_or(format!("unknown format {}", value))?)
+            }
+            "-r" | "--resolution" => self.resolution = Some(parse_resolution(value)?),
+            "--shadow-resolution" => self.shadow_resolution = Some(parse_resolution(value)?),
+            "-c" | "--camera" => self.camera = Some(value.into()),
+            "--eye" => self.eye = Some(parse_vector(value)?),
+            "--center" => self.center = Some(parse_vector(value)?),
+            "--up" => self.up = Some(parse_vector(value)?),
+            "--focal" => self.focal_length = Some(parse_positive(value)?),
+            "--fov" => {
+                let fov_y = parse_positive(value)?;
+                if fov_y >= 180f32 {
+                    return Err("field of view must be below 180 degrees".into());
+                }
+                self.fov_y = Some(fov_y)
+            }
+            "--shadows" => self.shadows = Some(value.parse()?),
+            "--ao" => self.ambient_occlusion = Some(parse_switch(value)?),
+            "--aa" => self.antialiasing = Some(parse_switch(value)?),
+            "-s" | "--shading" => self.shading = Some(value.parse()?),
+            "--turntable" => self.turntable = Some(parse_count(value)?),
+            "--frames" => self.frames = Some(parse_count(value)?),
+            "--fps" => self.frame_rate = Some(parse_frame_rate(value)?),
+            "--chroma" => {
+                self.chroma = Some(match value {
+                    "420" => ChromaSubsampling::Yuv420,
+                    "422" => ChromaSubsampling::Yuv422,
+                    "444" => ChromaSubsampling::Yuv444,
+                    "mono" => ChromaSubsampling::Mono,
+                    _ => return Err(format!("unknown chroma subsampling {}", value).into()),
+                })
+            }
+            "--dither" => self.dithering = Some(parse_switch(value)?),
+            "--loop" => {
+                self.loop_count = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a valid count", value))?,
+                )
+            }
+            _ => return Err("unknown option".into()),
+        }
+        Ok(())
+    }
+}
+
+fn parse_resolution(value: &str) -> utils::Result<(u32, u32)> {
+    let (width, height) = value.split_once('x').ok_or("expected <width>x<height>")?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("{} is not a valid resolution", value).into()),
+    }
+}
+
+fn parse_vector(value: &str) -> utils::Result<Vec3f32> {
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| format!("{} is not a vector", value))?;
+    if !components.iter().all(|component| component.is_finite()) {
+        return Err(format!("{} is not a vector", value).into());
+    }
+    if components.len() != 3 {
+        return Err("expected <x>,<y>,<z>".into());
+    }
+    Ok(Vec3f32::new_from_vec(&components))
+}
+
+fn parse_positive(value: &str) -> utils::Result<f32> {
+    match value.parse::<f32>() {
+        Ok(number) if number > 0f32 && number.is_finite() => Ok(number),
+        _ => Err(format!("{} is not a positive number", value).into()),
+    }
+}
+
+fn parse_count(value: &str) -> utils::Result<usize> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} is not a positive count", value).into()),
+    }
+}
+
+fn parse_frame_rate(value: &str) -> utils::Result<(u32, u32)> {
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    match (numerator.parse::<u32>(), denominator.parse::<u32>()) {
+        (Ok(numerator), Ok(denominator)) if numerator > 0 && denominator > 0 => {
+            Ok((numerator, denominator))
+        }
+        _ => Err(format!("{} is not a valid frame rate", value).into()),
+    }
+}
+
+fn parse_switch(value: &str) -> utils::Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got {}", value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> utils::Result<Options> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(options) => panic!("parsed {:?} as {:?}", args, options),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn values() {
+        let options = parse(&[
+            "scene.scene",
+            "-o",
+            "out.png",
+            "--resolution=640x480",
+            "--eye",
+            "1, 2,3",
+            "--fov=45",
+            "--fps=30000/1001",
+            "--aa",
+            "off",
+        ])
+        .unwrap();
+        assert!(!options.help);
+        assert_eq!(options.input, Some(PathBuf::from("scene.scene")));
+        assert_eq!(options.output, Some(PathBuf::from("out.png")));
+        assert_eq!(options.resolution, Some((640, 480)));
+        let eye = options.eye.unwrap();
+        assert_eq!([eye[0], eye[1], eye[2]], [1f32, 2f32, 3f32]);
+        assert_eq!(options.fov_y, Some(45f32));
+        assert_eq!(options.frame_rate, Some((30000, 1001)));
+        assert_eq!(options.antialiasing, Some(false));
+        assert_eq!(options.format, None);
+
+        // an inline value may be empty or contain another `=`
+        assert_eq!(parse(&["-o="]).unwrap().output, Some(PathBuf::from("")));
+        assert_eq!(
+            parse(&["--output=a=b.tga"]).unwrap().output,
+            Some(PathBuf::from("a=b.tga"))
+        );
+        assert!(parse(&["-h"]).unwrap().help);
+        assert_eq!(parse(&["-"]).unwrap().input, Some(PathBuf::from("-")));
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(parse_error(&["-o"]), "-o needs a value");
+        assert_eq!(
+            parse_error(&["input", "--resolution"]),
+            "--resolution needs a value"
+        );
+        // the next argument is taken as the value, even if it is an option
+        let options = parse(&["--camera", "--aa", "on"]).unwrap();
+        assert_eq!(options.camera.as_deref(), Some("--aa"));
+        assert_eq!(options.antialiasing, None);
+        assert_eq!(options.input, Some(PathBuf::from("on")));
+    }
+
+    #[test]
+    fn focal_and_fov() {
+        assert_eq!(
+            parse_error(&["--focal", "2", "--fov", "60"]),
+            "--focal and --fov cannot be used together"
+        );
+        assert_eq!(
+            parse_error(&["--fov=60", "--focal=2"]),
+            "--focal and --fov cannot be used together"
+        );
+        assert_eq!(
+            parse_error(&["--fov", "180"]),
+            "--fov: field of view must be below 180 degrees"
+        );
+        for value in ["0", "-1", "inf", "NaN", "two"] {
+            assert_eq!(
+                parse_error(&["--focal", value]),
+                format!("--focal: {} is not a positive number", value)
+            );
+        }
+    }
+
+    #[test]
+    fn bad_values() {
+        for value in [
+            "640",
+            "640x",
+            "x480",
+            "0x480",
+            "640x0",
+            "-1x480",
+            "640x480x1",
+        ] {
+            assert!(
+                parse_error(&["-r", value]).starts_with("-r: "),
+                "{:?}",
+                value
+            );
+        }
+        for (args, error) in [
+            (&["--eye", "1,2"][..], "--eye: expected <x>,<y>,<z>"),
+            (&["--eye", "1,inf,2"], "--eye: 1,inf,2 is not a vector"),
+            (&["--frames", "0"], "--frames: 0 is not a positive count"),
+            (&["--fps", "30/0"], "--fps: 30/0 is not a valid frame rate"),
+            (&["--format", "jpg"], "--format: unknown format jpg"),
+            (
+                &["--chroma", "411"],
+                "--chroma: unknown chroma subsampling 411",
+            ),
+            (&["--loop", "65536"], "--loop: 65536 is not a valid count"),
+            (&["--frobnicate", "1"], "--frobnicate: unknown option"),
+            (&["a", "b"], "unexpected argument b"),
+        ] {
+            assert_eq!(parse_error(args), error, "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,531 @@
+use crate::{
+    render::blend::BlendState,
+    texture::{Texture, TextureColor},
+    utils,
+};
+
+pub const DEPTH_CLEAR_VALUE: f32 = f32::MAX;
+pub const REVERSED_DEPTH_CLEAR_VALUE: f32 = 0f32;
+
+/// Render target owning a color attachment together with matching depth and
+/// optional 8-bit stencil attachments. Depth and stencil are stored row-major,
+/// indexed by `x + y * width` like the pixels of the color texture.
+///
+/// With more than one sample per pixel, depth and stencil hold `samples`
+/// consecutive values per pixel (indexed by `(x + y * width) * samples + s`),
+/// color is rendered into a separate per-sample buffer and `resolve` averages
+/// it into the color texture.
+///
+/// Depth normally grows with distance. Reversed-Z framebuffers store depth the
+/// other way round, as produced by the reversed projections in `render`, and
+/// are cleared to `REVERSED_DEPTH_CLEAR_VALUE` instead.
+///
+/// Optionally, every pixel also keeps a list of transparent fragments (an
+/// A-buffer). They are recorded in any order and composited back to front
+/// over the opaque color by `resolve`.
+///
+/// A framebuffer may also be a tile cut out of a larger one, in which case
+/// `origin` is the screen position of its first pixel. The rasterizer works in
+/// screen coordinates and only touches pixels inside the tile.
+pub struct Framebuffer {
+    origin: (u32, u32),
+    width: u32,
+    height: u32,
+    samples: u32,
+    reversed_z: bool,
+    color: Texture,
+    sample_color: Option<Box<[TextureColor]>>,
+    depth: Box<[f32]>,
+    stencil: Option<Box<[u8]>>,
+    fragments: Option<FragmentLists>,
+}
+
+const END_OF_LIST: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Fragment {
+    depth: f32,
+    color: TextureColor,
+    // samples of the pixel covered by the fragment, bit `s` for sample `s`
+    coverage: u32,
+    next: u32,
+}
+
+// Per-pixel singly linked lists of fragments, allocated from one shared pool.
+// New fragments are pushed at the head, so a list runs from the most recent
+// fragment to the oldest.
+struct FragmentLists {
+    heads: Box<[u32]>,
+    nodes: Vec<Fragment>,
+}
+
+impl FragmentLists {
+    fn new(pixels: usize) -> Self {
+        FragmentLists {
+            heads: vec![END_OF_LIST; pixels].into_boxed_slice(),
+            nodes: vec![],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.heads.fill(END_OF_LIST);
+        self.nodes.clear();
+    }
+
+    fn push(&mut self, pixel: usize, fragment: Fragment) {
+        self.nodes.push(Fragment {
+            next: self.heads[pixel],
+            ..fragment
+        });
+        self.heads[pixel] = (self.nodes.len() - 1) as u32;
+    }
+
+    // fragments of `pixel` in the order they were pushed
+    fn collect(&self, pixel: usize) -> Vec<Fragment> {
+        let mut fragments = vec![];
+        let mut node = self.heads[pixel];
+        while node != END_OF_LIST {
+            fragments.push(self.nodes[node as usize]);
+            node = self.nodes[node as usize].next;
+        }
+        fragments.reverse();
+        fragments
+    }
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            origin: (0, 0),
+            width,
+            height,
+            samples: 1,
+            reversed_z: false,
+            color: Texture::new(width, height),
+            sample_color: None,
+            depth: vec![DEPTH_CLEAR_VALUE; (width * height) as usize].into_boxed_slice(),
+            stencil: None,
+            fragments: None,
+        }
+    }
+
+    pub fn new_with_stencil(width: u32, height: u32) -> Self {
+        let mut framebuffer = Framebuffer::new(width, height);
+        framebuffer.stencil = Some(vec![0u8; (width * height) as usize].into_boxed_slice());
+        framebuffer
+    }
+
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_origin(&self) -> (u32, u32) {
+        self.origin
+    }
+
+    pub fn get_samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Switches to `samples` (1, 2, 4 or 8) samples per pixel. Like `resize`,
+    /// this reallocates the attachments and discards their contents.
+    pub fn set_samples(&mut self, samples: u32) -> utils::Result<()> {
+        if !matches!(samples, 1 | 2 | 4 | 8) {
+            return Err("unsupported sample count".into());
+        }
+
+        let size = (self.width * self.height * samples) as usize;
+        self.samples = samples;
+        self.color = Texture::new(self.width, self.height);
+        self.sample_color = if samples > 1 {
+            Some(vec![Default::default(); size].into_boxed_slice())
+        } else {
+            None
+        };
+        self.depth = vec![self.get_depth_clear_value(); size].into_boxed_slice();
+        if self.stencil.is_some() {
+            self.stencil = Some(vec![0u8; size].into_boxed_slice());
+        }
+        if let Some(fragments) = &mut self.fragments {
+            fragments.clear();
+        }
+        Ok(())
+    }
+
+    /// Adds or removes the per-pixel transparent fragment lists.
+    pub fn set_fragment_lists(&mut self, enabled: bool) {
+        self.fragments = if enabled {
+            Some(FragmentLists::new((self.width * self.height) as usize))
+        } else {
+            None
+        };
+    }
+
+    pub fn has_fragment_lists(&self) -> bool {
+        self.fragments.is_some()
+    }
+
+    /// Records a transparent fragment for pixel (`x`, `y`). `coverage` has
+    /// bit `s` set for every sample `s` the fragment covers.
+    pub fn push_fragment(
+        &mut self,
+        x: u32,
+        y: u32,
+        depth: f32,
+        color: TextureColor,
+        coverage: u32,
+    ) -> utils::Result<()> {
+        if x >= self.width || y >= self.height {
+            return Err("illegal arguments".into());
+        }
+
+        match &mut self.fragments {
+            Some(fragments) => {
+                let fragment = Fragment {
+                    depth,
+                    color,
+                    coverage,
+                    next: END_OF_LIST,
+                };
+                fragments.push((x + y * self.width) as usize, fragment);
+                Ok(())
+            }
+            None => Err("framebuffer has no fragment lists".into()),
+        }
+    }
+
+    /// Total number of fragments stored in the fragment lists.
+    pub fn get_fragment_count(&self) -> usize {
+        self.fragments
+            .as_ref()
+            .map_or(0, |fragments| fragments.nodes.len())
+    }
+
+    pub fn is_reversed_z(&self) -> bool {
+        self.reversed_z
+    }
+
+    /// Switches the direction of depth values and clears the depth attachment
+    /// to the matching clear value.
+    pub fn set_reversed_z(&mut self, reversed_z: bool) {
+        self.reversed_z = reversed_z;
+        self.clear_depth(self.get_depth_clear_value());
+    }
+
+    pub fn get_depth_clear_value(&self) -> f32 {
+        if self.reversed_z {
+            REVERSED_DEPTH_CLEAR_VALUE
+        } else {
+            DEPTH_CLEAR_VALUE
+        }
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        self.stencil.is_some()
+    }
+
+    pub fn get_color_attachment(&self) -> &Texture {
+        &self.color
+    }
+
+    pub fn get_color_attachment_mut(&mut self) -> &mut Texture {
+        &mut self.color
+    }
+
+    pub fn get_sample_color_attachment(&self) -> Option<&[TextureColor]> {
+        self.sample_color.as_deref()
+    }
+
+    pub fn get_sample_color_attachment_mut(&mut self) -> Option<&mut [TextureColor]> {
+        self.sample_color.as_deref_mut()
+    }
+
+    pub fn get_depth_attachment(&self) -> &[f32] {
+        &self.depth
+    }
+
+    pub fn get_depth_attachment_mut(&mut self) -> &mut [f32] {
+        &mut self.depth
+    }
+
+    pub fn get_stencil_attachment(&self) -> Option<&[u8]> {
+        self.stencil.as_deref()
+    }
+
+    pub fn get_stencil_attachment_mut(&mut self) -> Option<&mut [u8]> {
+        self.stencil.as_deref_mut()
+    }
+
+    /// Consumes the framebuffer, keeping only its (resolved) color attachment.
+    pub fn into_color_attachment(self) -> Texture {
+        self.color
+    }
+
+    pub fn get_depth(&self, x: u32, y: u32) -> utils::Result<f32> {
+        if x >= self.width || y >= self.height {
+            return Err("illegal arguments".into());
+        }
+
+        Ok(self.depth[((x + y * self.width) * self.samples) as usize])
+    }
+
+    pub fn set_depth(&mut self, x: u32, y: u32, depth: f32) -> utils::Result<()> {
+        if x >= self.width || y >= self.height {
+            return Err("illegal arguments".into());
+        }
+
+        self.depth[((x + y * self.width) * self.samples) as usize] = depth;
+        Ok(())
+    }
+
+    pub fn get_stencil(&self, x: u32, y: u32) -> utils::Result<u8> {
+        if x >= self.width || y >= self.height {
+            return Err("illegal arguments".into());
+        }
+
+        match &self.stencil {
+            Some(stencil) => Ok(stencil[((x + y * self.width) * self.samples) as usize]),
+            None => Err("framebuffer has no stencil attachment".into()),
+        }
+    }
+
+    pub fn set_stencil(&mut self, x: u32, y: u32, value: u8) -> utils::Result<()> {
+        if x >= self.width || y >= self.height {
+            return Err("illegal arguments".into());
+        }
+
+        match &mut self.stencil {
+            Some(stencil) => {
+                stencil[((x + y * self.width) * self.samples) as usize] = value;
+                Ok(())
+            }
+            None => Err("framebuffer has no stencil attachment".into()),
+        }
+    }
+
+    pub fn clear_color(&mut self, color: TextureColor) {
+        self.color.clear(color);
+        if let Some(sample_color) = &mut self.sample_color {
+            sample_color.fill(color);
+        }
+    }
+
+    pub fn clear_depth(&mut self, depth: f32) {
+        self.depth.fill(depth);
+    }
+
+    pub fn clear_stencil(&mut self, value: u8) {
+        if let Some(stencil) = &mut self.stencil {
+            stencil.fill(value);
+        }
+    }
+
+    pub fn clear_fragments(&mut self) {
+        if let Some(fragments) = &mut self.fragments {
+            fragments.clear();
+        }
+    }
+
+    /// Clears every attachment: color to `color`, depth to the depth clear
+    /// value, stencil (if present) to zero and the fragment lists to empty.
+    pub fn clear(&mut self, color: TextureColor) {
+        self.clear_color(color);
+        self.clear_depth(self.get_depth_clear_value());
+        self.clear_stencil(0);
+        self.clear_fragments();
+    }
+
+    /// Copies the `width` x `height` region starting at (`x`, `y`) out of every
+    /// attachment into a new framebuffer whose origin is that screen position.
+    /// The tile gets fragment lists if the framebuffer has them, but they
+    /// start out empty.
+    pub fn read_tile(&self, x: u32, y: u32, width: u32, height: u32) -> utils::Result<Framebuffer> {
+        if x + width > self.width || y + height > self.height {
+            return Err("illegal arguments".into());
+        }
+
+        let mut tile = if self.has_stencil() {
+            Framebuffer::new_with_stencil(width, height)
+        } else {
+            Framebuffer::new(width, height)
+        };
+        tile.reversed_z = self.reversed_z;
+        tile.set_samples(self.samples)?;
+        tile.set_fragment_lists(self.has_fragment_lists());
+        tile.origin = (self.origin.0 + x, self.origin.1 + y);
+
+        let samples = self.samples as usize;
+        for j in 0..height {
+            let src = ((y + j) * self.width + x) as usize * samples;
+            let dst = (j * width) as usize * samples;
+            let len = width as usize * samples;
+
+            tile.depth[dst..dst + len].copy_from_slice(&self.depth[src..src + len]);
+            if let (Some(tile_stencil), Some(stencil)) = (&mut tile.stencil, &self.stencil) {
+                tile_stencil[dst..dst + len].copy_from_slice(&stencil[src..src + len]);
+            }
+            if let (Some(tile_color), Some(color)) = (&mut tile.sample_color, &self.sample_color) {
+                tile_color[dst..dst + len].copy_from_slice(&color[src..src + len]);
+            }
+            for i in 0..width {
+                tile.color
+                    .set_color(i, j, self.color.get_color(x + i, y + j)?)?;
+            }
+        }
+
+        Ok(tile)
+    }
+
+    /// Writes a tile produced by `read_tile` back to where it was taken from.
+    /// Fragments recorded in the tile are appended to the fragment lists.
+    pub fn write_tile(&mut self, tile: &Framebuffer) -> utils::Result<()> {
+        if tile.origin.0 < self.origin.0 || tile.origin.1 < self.origin.1 {
+            return Err("illegal arguments".into());
+        }
+        let (x, y) = (tile.origin.0 - self.origin.0, tile.origin.1 - self.origin.1);
+        if x + tile.width > self.width || y + tile.height > self.height {
+            return Err("illegal arguments".into());
+        }
+
+        if tile.samples != self.samples {
+            return Err("sample count mismatch".into());
+        }
+
+        let samples = self.samples as usize;
+        for j in 0..tile.height {
+            let src = (j * tile.width) as usize * samples;
+            let dst = ((y + j) * self.width + x) as usize * samples;
+            let len = tile.width as usize * samples;
+
+            self.depth[dst..dst + len].copy_from_slice(&tile.depth[src..src + len]);
+            if let (Some(stencil), Some(tile_stencil)) = (&mut self.stencil, &tile.stencil) {
+                stencil[dst..dst + len].copy_from_slice(&tile_stencil[src..src + len]);
+            }
+            if let (Some(color), Some(tile_color)) = (&mut self.sample_color, &tile.sample_color) {
+                color[dst..dst + len].copy_from_slice(&tile_color[src..src + len]);
+            }
+            for i in 0..tile.width {
+                self.color
+                    .set_color(x + i, y + j, tile.color.get_color(i, j)?)?;
+            }
+        }
+
+        if let (Some(fragments), Some(tile_fragments)) = (&mut self.fragments, &tile.fragments) {
+            for j in 0..tile.height {
+                for i in 0..tile.width {
+                    let pixel = ((y + j) * self.width + x + i) as usize;
+                    for fragment in tile_fragments.collect((j * tile.width + i) as usize) {
+                        fragments.push(pixel, fragment);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Composites the fragment lists, if any, and averages the samples of
+    /// every pixel into the color texture.
+    ///
+    /// Each sample gets the fragments covering it that lie in front of its
+    /// depth blended over its color, farthest first, with straight alpha
+    /// "over" blending. The fragment lists are empty afterwards. Single
+    /// sampled framebuffers render into the texture directly, so without
+    /// fragment lists there is nothing to do for them.
+    pub fn resolve(&mut self) {
+        if let Some(fragments) = self.fragments.take() {
+            self.composite_fragments(&fragments);
+            self.fragments = Some(FragmentLists::new(fragments.heads.len()));
+        }
+
+        let sample_color = match &self.sample_color {
+            Some(sample_color) => sample_color,
+            None => return,
+        };
+
+        let samples = self.samples as usize;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = (x + y * self.width) as usize * samples;
+                let (mut r, mut g, mut b, mut a) = (0u32, 0u32, 0u32, 0u32);
+                for color in &sample_color[index..index + samples] {
+                    r += color.r as u32;
+                    g += color.g as u32;
+                    b += color.b as u32;
+                    a += color.a as u32;
+                }
+
+                let samples = samples as u32;
+                let color = TextureColor {
+                    r: ((r + samples / 2) / samples) as u8,
+                    g: ((g + samples / 2) / samples) as u8,
+                    b: ((b + samples / 2) / samples) as u8,
+                    a: ((a + samples / 2) / samples) as u8,
+                };
+                self.color.set_color(x, y, color).unwrap();
+            }
+        }
+    }
+
+    fn composite_fragments(&mut self, fragments: &FragmentLists) {
+        let samples = self.samples as usize;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pixel = (x + y * self.width) as usize;
+                if fragments.heads[pixel] == END_OF_LIST {
+                    continue;
+                }
+
+                // back to front, fragments at equal depth in submission order
+                let mut list = fragments.collect(pixel);
+                if self.reversed_z {
+                    list.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+                } else {
+                    list.sort_by(|a, b| b.depth.total_cmp(&a.depth));
+                }
+
+                for s in 0..samples {
+                    let index = pixel * samples + s;
+                    let mut color = match &self.sample_color {
+                        Some(sample_color) => sample_color[index],
+                        None => self.color.get_color(x, y).unwrap(),
+                    };
+                    for fragment in list.iter() {
+                        let in_front = if self.reversed_z {
+                            fragment.depth >= self.depth[index]
+                        } else {
+                            fragment.depth <= self.depth[index]
+                        };
+                        if fragment.coverage & (1 << s) != 0 && in_front {
+                            color = BlendState::ALPHA.blend(fragment.color, color);
+                        }
+                    }
+                    match &mut self.sample_color {
+                        Some(sample_color) => sample_color[index] = color,
+                        None => self.color.set_color(x, y, color).unwrap(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reallocates all attachments with the new dimensions. Previous contents
+    /// are discarded and the attachments are left in their cleared state.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let (has_stencil, samples) = (self.has_stencil(), self.samples);
+        let (has_fragment_lists, reversed_z) = (self.has_fragment_lists(), self.reversed_z);
+
+        *self = if has_stencil {
+            Framebuffer::new_with_stencil(width, height)
+        } else {
+            Framebuffer::new(width, height)
+        };
+        self.reversed_z = reversed_z;
+        self.set_samples(samples).unwrap();
+        self.set_fragment_lists(has_fragment_lists);
+    }
+}
@@ -1,8 +1,9 @@
-#![feature(generic_const_exprs)]
 #![feature(portable_simd)]
 pub mod frame;
 pub mod math;
 pub mod model;
 pub mod render;
+pub mod scene;
 pub mod texture;
 pub mod utils;
+pub mod video;
@@ -1,65 +1,91 @@
 use std::{
-    cmp::{self, min},
+    cmp, env,
     f32::consts::PI,
-    path::Path,
-    vec,
+    path::{Path, PathBuf},
+    process,
 };
 
+use cli::{Format, Options};
+
 use librender::{
+    frame::{Framebuffer, DEPTH_CLEAR_VALUE},
     math::{
         matrix::Matrix,
         vector::{Vec2f32, Vec3f32, Vec4f32, Vector},
     },
-    model::{self, Model},
-    render::{self, Shader},
-    texture::{self, Texture, TextureColor},
+    model::Model,
+    render::{
+        self,
+        shadow_volume::{self, ShadowVolume, ShadowVolumeShader},
+        CullMode, Pipeline, Shader, Varyings,
+    },
+    scene::{
+        self,
+        animation::{Animation, CameraPath},
+        file::{RenderSettings, SceneFile, Shading, Shadows},
+        Camera, Light, Material, Mesh, MeshRef, Node, Scene,
+    },
+    texture::{self, netpbm, Texture, TextureColor},
+    utils,
+    video::{gif::GifWriter, y4m::Y4mWriter, FrameSink},
 };
 
-const WIDTH: u32 = 1600;
-const HEIGHT: u32 = 1600;
+// view-space surface attributes, plus the directions in which the texture
+// coordinates u and v grow for normal mapping
+#[derive(Clone)]
+struct AVaryings {
+    uv: Vec2f32,
+    normal: Vec4f32,
+    position: Vec4f32,
+    tangent: Vec4f32,
+    bitangent: Vec4f32,
+}
 
-const WIDTH_SHADOW: u32 = 1600;
-const HEIGHT_SHADOW: u32 = 1600;
+impl Varyings for AVaryings {
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self {
+        AVaryings {
+            uv: Varyings::interpolate(&values.clone().map(|value| value.uv), weights),
+            normal: Varyings::interpolate(&values.clone().map(|value| value.normal), weights),
+            position: Varyings::interpolate(&values.clone().map(|value| value.position), weights),
+            tangent: Varyings::interpolate(&values.clone().map(|value| value.tangent), weights),
+            bitangent: Varyings::interpolate(&values.clone().map(|value| value.bitangent), weights),
+        }
+    }
+}
 
+#[derive(Clone)]
 struct AShader<'a> {
     model_view: Matrix<f32, 4, 4>,
     projection: Matrix<f32, 4, 4>,
-    viewport: Matrix<f32, 4, 4>,
     model_view_inv_t: Matrix<f32, 4, 4>,
-    uv: Matrix<f32, 2, 3>,
-    normal: Matrix<f32, 4, 4>,
-    view: Matrix<f32, 4, 4>,
     light: Vec4f32,
     normal_map: Option<&'a Texture>,
     specular_map: Option<&'a Texture>,
     diffuse_map: Option<&'a Texture>,
-    model: Option<&'a Model>,
-    shadow_zbuffer: Option<&'a Vec<f32>>,
+    shadow_zbuffer: Option<&'a [f32]>,
+    shadow_size: (u32, u32),
     shadow_matrix: Option<Matrix<f32, 4, 4>>,
+    shading: Shading,
 }
 
 impl<'a> AShader<'a> {
     pub fn new(
         model_view: Matrix<f32, 4, 4>,
         projection: Matrix<f32, 4, 4>,
-        viewport: Matrix<f32, 4, 4>,
         light: Vec4f32,
     ) -> AShader<'a> {
         AShader {
             model_view,
             projection,
-            viewport,
             model_view_inv_t: model_view.inv().transpose(),
-            uv: Matrix::new(),
-            normal: Matrix::new(),
-            view: Matrix::new(),
             light: (&model_view * &light).normalize(),
             normal_map: None,
             specular_map: None,
             diffuse_map: None,
-            model: None,
             shadow_zbuffer: None,
+            shadow_size: (0, 0),
             shadow_matrix: None,
+            shading: Shading::Textured,
         }
     }
 
@@ -75,27 +101,34 @@ impl<'a> AShader<'a> {
         self.diffuse_map = diffuse_map
     }
 
-    pub fn set_model(&mut self, model: &'a Model) {
-        self.model = Some(model)
-    }
-
-    pub fn set_shadow_zbuffer(&mut self, buffer: &'a Vec<f32>) {
-        self.shadow_zbuffer = Some(buffer)
+    pub fn set_shadow_zbuffer(&mut self, buffer: &'a [f32], width: u32, height: u32) {
+        self.shadow_zbuffer = Some(buffer);
+        self.shadow_size = (width, height)
     }
 
     pub fn set_shadow_matrix(&mut self, matrix: Matrix<f32, 4, 4>) {
         self.shadow_matrix = Some(matrix)
     }
 
-    fn get_normal(&self, uv: &Vec2f32) -> Vec3f32 {
-        let color = self.normal_map.unwrap().sample(uv);
-        &(&(&Vec3f32::new_from_vec(&vec![color.r as f32, color.g as f32, color.b as f32]) * 2f32)
+    pub fn set_shading(&mut self, shading: Shading) {
+        self.shading = shading
+    }
+
+    fn get_normal(normal_map: &Texture, uv: &Vec2f32) -> Vec3f32 {
+        let color = normal_map.sample(uv);
+        &(&(&Vec3f32::new_from_vec(&[color.r as f32, color.g as f32, color.b as f32]) * 2f32)
             / 255f32)
-            - &Vec3f32::new_from_vec(&vec![1f32, 1f32, 1f32])
+            - &Vec3f32::new_from_vec(&[1f32, 1f32, 1f32])
     }
 
-    fn max_horizon_angle(&self, zbuffer: &Vec<f32>, point: Vec2f32, dir: Vec2f32) -> f32 {
-        let point_z = zbuffer[(point[0] as u32 + point[1] as u32 * WIDTH) as usize];
+    fn max_horizon_angle(
+        &self,
+        zbuffer: &[f32],
+        (width, height): (u32, u32),
+        point: Vec2f32,
+        dir: Vec2f32,
+    ) -> f32 {
+        let point_z = zbuffer[(point[0] as u32 + point[1] as u32 * width) as usize];
 
         let mut res = 0f32;
         let mut step = 0f32;
@@ -106,23 +139,23 @@ impl<'a> AShader<'a> {
             let sample_point_y = sample_point[1] as i32;
 
             if sample_point_x < 0
-                || sample_point_x as u32 >= WIDTH
+                || sample_point_x as u32 >= width
                 || sample_point_y < 0
-                || sample_point_y as u32 >= HEIGHT
+                || sample_point_y as u32 >= height
             {
                 break;
             }
 
             let dis = (&sample_point - &point).norm_l2();
             if dis < 1f32 {
-                step = step + 1.0;
+                step += 1.0;
                 continue;
             }
             let (sample_point_x, sample_point_y) = (sample_point_x as u32, sample_point_y as u32);
-            let sample_point_z = zbuffer[(sample_point_x + sample_point_y * WIDTH) as usize];
+            let sample_point_z = zbuffer[(sample_point_x + sample_point_y * width) as usize];
             let sample_angle = ((point_z - sample_point_z) / dis).atan();
             if point_z - sample_point_z > 1e-1 {
-                step = step + 1.0;
+                step += 1.0;
                 continue;
             }
 
@@ -131,31 +164,48 @@ impl<'a> AShader<'a> {
                 max_dis = dis;
             }
 
-            step = step + 1.0;
+            step += 1.0;
         }
 
         res * (1f32 - max_dis / 10f32)
     }
 
-    fn ao(&self, zbuffer: &Vec<f32>, frame: &mut Texture) {
-        for x in 0..frame.get_width() {
-            for y in 0..frame.get_height() {
-                if zbuffer[(x + y * frame.get_width()) as usize] > 1e5 {
+    fn ao(&self, framebuffer: &mut Framebuffer) {
+        // horizons are searched in view-space depth, of the first sample of
+        // multisampled pixels
+        let zbuffer: Vec<f32> = framebuffer
+            .get_depth_attachment()
+            .iter()
+            .step_by(framebuffer.get_samples() as usize)
+            .map(|&depth| {
+                if depth == DEPTH_CLEAR_VALUE {
+                    depth
+                } else {
+                    render::view_depth(&self.projection, depth)
+                }
+            })
+            .collect();
+
+        let size = (framebuffer.get_width(), framebuffer.get_height());
+        for x in 0..framebuffer.get_width() {
+            for y in 0..framebuffer.get_height() {
+                if zbuffer[(x + y * framebuffer.get_width()) as usize] > 1e5 {
                     continue;
                 }
 
                 let mut ao = 0f32;
                 let mut alpha = 0f32;
 
-                let point = Vec2f32::new_from_vec(&vec![x as f32, y as f32]);
+                let point = Vec2f32::new_from_vec(&[x as f32, y as f32]);
                 for _ in 0..8 {
-                    let dir = Vec2f32::new_from_vec(&vec![alpha.cos(), alpha.sin()]);
-                    ao += PI / 2.0 - self.max_horizon_angle(&zbuffer, point, dir);
+                    let dir = Vec2f32::new_from_vec(&[alpha.cos(), alpha.sin()]);
+                    ao += PI / 2.0 - self.max_horizon_angle(&zbuffer, size, point, dir);
                     alpha += PI / 4.0;
                 }
 
                 ao /= (PI / 2.0) * 8.0;
                 ao = ao.powf(100f32);
+                let frame = framebuffer.get_color_attachment_mut();
                 let mut color = frame.get_color(x, y).unwrap();
                 color.r = (color.r as f32 * ao) as u8;
                 color.g = (color.g as f32 * ao) as u8;
@@ -164,144 +214,119 @@ impl<'a> AShader<'a> {
             }
         }
     }
-
-    fn ssaa(&self, frame: &Texture) -> Texture {
-        let mut new_frame = texture::Texture::new(frame.get_width() / 2, frame.get_height() / 2);
-
-        for x in 0..new_frame.get_width() {
-            for y in 0..new_frame.get_height() {
-                let (x_up, y_up) = (2 * x as i32, 2 * y as i32);
-                let mut cnt = 0u32;
-                let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
-
-                for i in -1i32..=1 {
-                    for j in -1i32..=1 {
-                        let (nx, ny) = (x_up + i, y_up + j);
-                        if nx < 0
-                            || nx >= frame.get_width() as i32
-                            || ny < 0
-                            || ny >= frame.get_height() as i32
-                        {
-                            continue;
-                        }
-
-                        r += frame.get_color(nx as u32, ny as u32).unwrap().r as u32;
-                        g += frame.get_color(nx as u32, ny as u32).unwrap().g as u32;
-                        b += frame.get_color(nx as u32, ny as u32).unwrap().b as u32;
-                        cnt += 1;
-                    }
-                }
-
-                let r = min(r / cnt, 255u32) as u8;
-                let g = min(g / cnt, 255u32) as u8;
-                let b = min(b / cnt, 255u32) as u8;
-                new_frame
-                    .set_color(x, y, TextureColor { r, g, b, a: 255 })
-                    .unwrap();
-            }
-        }
-
-        new_frame
-    }
 }
 
 impl<'a> Shader for AShader<'a> {
-    fn get_model_view(&self) -> &Matrix<f32, 4, 4> {
-        &self.model_view
-    }
-
-    fn get_projection(&self) -> &Matrix<f32, 4, 4> {
-        &self.projection
-    }
-
-    fn get_viewport(&self) -> &Matrix<f32, 4, 4> {
-        &self.viewport
-    }
+    type Varyings = AVaryings;
+
+    fn vertex(
+        &mut self,
+        model: &Model,
+        face_index: usize,
+        nth_vertex: usize,
+    ) -> (Vec4f32, AVaryings) {
+        // the tangent frame and the flat normal come from the whole face, so
+        // this shader can't be used with draw_indexed
+        let view = [0, 1, 2].map(|nth| &self.model_view * &model.get_vertex(face_index, nth));
+        let uv = [0, 1, 2].map(|nth| model.get_uv(face_index, nth));
+        let mut normal: Vec4f32 = (&self.model_view_inv_t
+            * &model.get_normal(face_index, nth_vertex))
+            .project::<3>()
+            .embed(0f32);
+        if self.shading == Shading::Flat {
+            // the face normal, on the side of the vertex normal
+            let face_normal = (&view[1] - &view[0]).cross(&(&view[2] - &view[0]));
+            normal = if &face_normal * &normal < 0f32 {
+                &face_normal * -1f32
+            } else {
+                face_normal
+            };
+        }
 
-    fn vertex(&mut self, face_index: usize, nth_vertex: usize) -> Vec4f32 {
-        self.uv.set_col(
-            nth_vertex,
-            &self.model.unwrap().get_uv(face_index, nth_vertex),
-        );
-        self.normal.set_col(
-            nth_vertex,
-            &(&self.model_view_inv_t * &self.model.unwrap().get_normal(face_index, nth_vertex))
-                .project::<3>()
-                .embed(0f32),
-        );
-        let view_space = &self.model_view * &self.model.unwrap().get_vertex(face_index, nth_vertex);
-        self.view.set_col(nth_vertex, &view_space);
-        &self.projection * &view_space
+        let mut a: Matrix<f32, 4, 4> = Matrix::new();
+        a[0] = &view[1] - &view[0];
+        a[1] = &view[2] - &view[0];
+        a[2] = normal.normalize();
+        a[3] = Vec4f32::new_from_vec(&[0f32, 0f32, 0f32, 1f32]);
+        let a_inv = a.inv();
+        let tangent = &a_inv
+            * &Vec4f32::new_from_vec(&[uv[1][0] - uv[0][0], uv[2][0] - uv[0][0], 0f32, 0f32]);
+        let bitangent = &a_inv
+            * &Vec4f32::new_from_vec(&[uv[1][1] - uv[0][1], uv[2][1] - uv[0][1], 0f32, 0f32]);
+
+        let position = view[nth_vertex];
+        let varyings = AVaryings {
+            uv: uv[nth_vertex],
+            normal,
+            position,
+            tangent,
+            bitangent,
+        };
+        (&self.projection * &position, varyings)
     }
 
-    fn fragment(&mut self, barycentric: &Vec3f32, color: &mut TextureColor) -> bool {
-        let barycentric_homo = barycentric.embed::<4>(0f32);
-        let normal_inter = (&self.normal * &barycentric_homo).normalize();
-        let uv_inter =
-            Vec2f32::new_from_array([&self.uv[0] * barycentric, &self.uv[1] * barycentric]);
+    fn fragment(&mut self, varyings: &AVaryings, color: &mut TextureColor) -> bool {
+        let normal_inter = varyings.normal.normalize();
+        let uv_inter = varyings.uv;
 
-        let mut a: Matrix<f32, 4, 4> = Matrix::new();
-        a[0] = &self.view.get_col(1) - &self.view.get_col(0);
-        a[1] = &self.view.get_col(2) - &self.view.get_col(0);
-        a[2] = normal_inter;
-        a[3] = Vec4f32::new_from_vec(&vec![0f32, 0f32, 0f32, 1f32]);
-        let a_inv = a.inv();
-        let u = &a_inv
-            * &Vec4f32::new_from_vec(&vec![
-                self.uv[0][1] - self.uv[0][0],
-                self.uv[0][2] - self.uv[0][0],
-                0f32,
-                0f32,
-            ]);
-        let v = &a_inv
-            * &Vec4f32::new_from_vec(&vec![
-                self.uv[1][1] - self.uv[1][0],
-                self.uv[1][2] - self.uv[1][0],
-                0f32,
-                0f32,
-            ]);
-        let mut b: Matrix<f32, 4, 4> = Matrix::new();
-        b[0] = u.normalize();
-        b[1] = v.normalize();
-        b[2] = normal_inter;
-        b[3] = Vec4f32::new_from_vec(&vec![0f32, 0f32, 0f32, 1f32]);
-        b = b.transpose();
+        if self.shading == Shading::Normals {
+            let rgb =
+                &(&normal_inter + &Vec4f32::new_from_vec(&[1f32, 1f32, 1f32, 0f32])) * 127.5f32;
+            (color.r, color.g, color.b, color.a) =
+                (rgb[0] as u8, rgb[1] as u8, rgb[2] as u8, 255u8);
+            return true;
+        }
 
         let mut shadow = 1.0;
-        if let Some(_) = self.shadow_zbuffer {
-            let shadow_mapping_pos =
-                &self.shadow_matrix.unwrap() * &(&self.view * &barycentric_homo);
-            let depth = shadow_mapping_pos[2];
+        if let Some(shadow_zbuffer) = self.shadow_zbuffer {
+            let (shadow_width, shadow_height) = self.shadow_size;
+            let shadow_mapping_pos = &self.shadow_matrix.unwrap() * &varyings.position;
             let shadow_mapping_pos = &shadow_mapping_pos / shadow_mapping_pos[3];
+            let depth = shadow_mapping_pos[2];
 
             if shadow_mapping_pos[0] < 0f32
-                || shadow_mapping_pos[0] > WIDTH_SHADOW as f32
+                || shadow_mapping_pos[0] >= shadow_width as f32
                 || shadow_mapping_pos[1] < 0f32
-                || shadow_mapping_pos[1] > HEIGHT_SHADOW as f32
+                || shadow_mapping_pos[1] >= shadow_height as f32
             {
                 shadow = 1.0;
             } else {
                 let x: i32 = unsafe { shadow_mapping_pos[0].floor().to_int_unchecked() };
                 let y: i32 = unsafe { shadow_mapping_pos[1].floor().to_int_unchecked() };
 
-                let index = x + y * WIDTH_SHADOW as i32;
-                if depth - self.shadow_zbuffer.unwrap()[index as usize] > 1e-1 {
+                let index = x + y * shadow_width as i32;
+                // the bias is in depth buffer units, 1 - f / z for the pinhole
+                // light: a view space gap dz becomes about f * dz / z^2, so
+                // dz / f near the focal plane. With the light's f of sqrt(6)
+                // this is the 0.1 view space bias used before
+                if depth - shadow_zbuffer[index as usize] > 4e-2 {
                     shadow = 0.4;
                 }
             }
         }
 
-        let n = (&b * &self.get_normal(&uv_inter).embed(0f32)).normalize();
+        let n = match self.normal_map {
+            Some(normal_map) if self.shading == Shading::Textured => {
+                let mut b: Matrix<f32, 4, 4> = Matrix::new();
+                b[0] = varyings.tangent.normalize();
+                b[1] = varyings.bitangent.normalize();
+                b[2] = normal_inter;
+                b[3] = Vec4f32::new_from_vec(&[0f32, 0f32, 0f32, 1f32]);
+                b = b.transpose();
+                (&b * &Self::get_normal(normal_map, &uv_inter).embed(0f32)).normalize()
+            }
+            _ => normal_inter,
+        };
         let mut diffuse = &n * &self.light;
         if diffuse < 0f32 {
             diffuse = 0f32
         }
         let reflection = (&(&n * ((&n * &self.light) * 2f32)) - &self.light).normalize();
-        let specular = if let Some(_) = self.specular_map {
+        let specular = if let Some(specular_map) = self.specular_map {
             if -reflection[2] > 0f32 {
                 f32::powf(
                     -reflection[2],
-                    5f32 + self.specular_map.unwrap().sample(&uv_inter).b as f32,
+                    5f32 + specular_map.sample(&uv_inter).b as f32,
                 )
             } else {
                 0f32
@@ -310,7 +335,15 @@ impl<'a> Shader for AShader<'a> {
             0f32
         };
 
-        let diffuse_color = self.diffuse_map.unwrap().sample(&uv_inter);
+        let diffuse_color = match self.diffuse_map {
+            Some(diffuse_map) => diffuse_map.sample(&uv_inter),
+            None => TextureColor {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+        };
         color.r = cmp::min::<u32>(
             20 + (diffuse_color.r as f32 * shadow * (diffuse + specular)) as u32,
             255,
@@ -327,200 +360,505 @@ impl<'a> Shader for AShader<'a> {
 
         true
     }
-
-    fn run_once(&mut self, zbuffer: &mut Vec<f32>, frame: &mut Texture) {
-        for i in 0..self.model.unwrap().get_nfaces() {
-            let mut clip_triangle = [Vector::<f32, 4>::new(); 3];
-
-            for j in 0..3 {
-                clip_triangle[j] = self.vertex(i, j);
-            }
-
-            render::triangle_rasterize(&clip_triangle, self, zbuffer, frame);
-        }
-    }
 }
 
-struct ShadowShader<'a> {
-    model_view: Matrix<f32, 4, 4>,
-    projection: Matrix<f32, 4, 4>,
-    viewport: Matrix<f32, 4, 4>,
+#[derive(Clone)]
+struct ShadowShader {
     model_view_projection: Matrix<f32, 4, 4>,
-    model: Option<&'a Model>,
 }
 
-impl<'a> ShadowShader<'a> {
-    pub fn new(
-        model_view: Matrix<f32, 4, 4>,
-        projection: Matrix<f32, 4, 4>,
-        viewport: Matrix<f32, 4, 4>,
-    ) -> ShadowShader<'a> {
+impl ShadowShader {
+    pub fn new(model_view: Matrix<f32, 4, 4>, projection: Matrix<f32, 4, 4>) -> ShadowShader {
         ShadowShader {
-            model_view,
-            projection,
-            viewport,
             model_view_projection: &projection * &model_view,
-            model: None,
         }
     }
+}
+
+impl Shader for ShadowShader {
+    type Varyings = ();
+
+    fn vertex(&mut self, model: &Model, face_index: usize, nth_vertex: usize) -> (Vec4f32, ()) {
+        let vertex = model.get_vertex(face_index, nth_vertex);
+        (&self.model_view_projection * &vertex, ())
+    }
+
+    fn fragment(&mut self, _varyings: &(), _color: &mut TextureColor) -> bool {
+        true
+    }
+}
+
+// scene rendered when no input is given
+const DEFAULT_SCENE: &str = "scenes/diablo3.scene";
+
+// frames per second of videos when not given
+const DEFAULT_FRAME_RATE: (u32, u32) = (25, 1);
+
+// Scene of a single OBJ model scaled into the unit sphere around the origin,
+// with the `<stem>_diffuse.tga`, `<stem>_nm_tangent.tga` and
+// `<stem>_spec.tga` textures next to it when they exist, seen and lit like
+// the default scene.
+fn load_model_scene(path: &Path) -> utils::Result<SceneFile> {
+    let model = Model::new_from_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if model.vertices.is_empty() {
+        return Err(format!("{}: model has no vertices", path.display()).into());
+    }
 
-    pub fn set_model(&mut self, model: &'a Model) {
-        self.model = Some(model)
+    let mut min = model.vertices[0];
+    let mut max = model.vertices[0];
+    for vertex in model.vertices.iter() {
+        for i in 0..3 {
+            min[i] = min[i].min(vertex[i]);
+            max[i] = max[i].max(vertex[i]);
+        }
     }
+    let center = &(&min + &max) * 0.5f32;
+    let radius = model
+        .vertices
+        .iter()
+        .map(|vertex| (vertex - &center).norm_l2())
+        .fold(f32::EPSILON, f32::max);
+
+    let stem = path.with_extension("");
+    let load = |suffix: &str| -> utils::Result<Option<Texture>> {
+        let path = ["tga", "png"]
+            .iter()
+            .map(|extension| PathBuf::from(format!("{}_{}.{}", stem.display(), suffix, extension)))
+            .find(|path| path.exists());
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let texture =
+            texture::read_from_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some(texture))
+    };
+
+    let mut scene = Scene::new();
+    let mesh = scene.add_mesh(Mesh::new(model));
+    let material = scene.add_material(Material {
+        diffuse_map: load("diffuse")?,
+        normal_map: load("nm_tangent")?,
+        specular_map: load("spec")?,
+        cull_mode: CullMode::Back,
+    });
+    let mut node = Node::new("model");
+    node.transform = &render::scaling(Vec3f32::new_from_array([1f32 / radius; 3]))
+        * &render::translation((&center * -1f32).project::<3>());
+    node.mesh = Some(MeshRef { mesh, material });
+    scene.add_node(None, node)?;
+
+    let center: Vec4f32 = Vector::new_from_vec(&[0f32, 0f32, 0f32, 1f32]);
+    let up: Vec4f32 = Vector::new_from_vec(&[0f32, 1f32, 0f32, 0f32]);
+    let eye: Vec4f32 = Vector::new_from_vec(&[1f32, 1f32, 3f32, 1f32]);
+    let mut camera = Node::new_looking_at("camera", eye, center, up);
+    camera.camera = Some(Camera::Pinhole {
+        focal_length: (&center - &eye).norm_l2(),
+    });
+    scene.add_node(None, camera)?;
+
+    let light: Vec4f32 = Vector::new_from_vec(&[1f32, 2f32, 1f32, 1f32]);
+    let mut light_node = Node::new_looking_at("light", light, center, up);
+    light_node.light = Some(Light::Directional { intensity: 1f32 });
+    light_node.camera = Some(Camera::Pinhole {
+        focal_length: (&center - &light).norm_l2(),
+    });
+    scene.add_node(None, light_node)?;
+
+    Ok(SceneFile {
+        scene,
+        settings: Default::default(),
+        animation: None,
+    })
 }
 
-impl<'a> Shader for ShadowShader<'a> {
-    fn get_model_view(&self) -> &Matrix<f32, 4, 4> {
-        &self.model_view
+// Points the camera node as given by the options. Positions are in the space
+// of the parent of the node, and those not given are kept.
+fn set_camera(
+    scene: &mut Scene,
+    camera: usize,
+    options: &Options,
+    aspect: f32,
+) -> utils::Result<()> {
+    let node = scene.get_node_mut(camera);
+    if options.eye.is_some() || options.center.is_some() || options.up.is_some() {
+        let transform = node.transform;
+        let eye = options
+            .eye
+            .map_or(transform.get_col(3), |eye| eye.embed(1f32));
+        let center = options
+            .center
+            .map_or(&eye + &transform.get_col(2), |center| center.embed(1f32));
+        let up = options.up.map_or(transform.get_col(1), |up| up.embed(0f32));
+
+        let forward = &center - &eye;
+        if forward.norm_l2() == 0f32 || forward.cross(&up).norm_l2() == 0f32 {
+            return Err("camera direction and up cannot be parallel".into());
+        }
+        node.transform = render::lookat(eye, center, up).inv();
     }
 
-    fn get_projection(&self) -> &Matrix<f32, 4, 4> {
-        &self.projection
+    if let Some(focal_length) = options.focal_length {
+        node.camera = Some(Camera::Pinhole { focal_length });
     }
+    if let Some(fov_y) = options.fov_y {
+        node.camera = Some(Camera::Perspective {
+            fov_y: fov_y.to_radians(),
+            aspect,
+            near: 0.05,
+            far: 100f32,
+        });
+    }
+    Ok(())
+}
 
-    fn get_viewport(&self) -> &Matrix<f32, 4, 4> {
-        &self.viewport
+// The draws index texture coordinates and normals of every face corner.
+fn check_model(model: &Model) -> utils::Result<()> {
+    let ncorners = model.face_vertex_indices.len();
+    if model.face_texture_coordinate_indices.len() != ncorners {
+        return Err("model is missing texture coordinates".into());
+    }
+    if model.face_vertex_normal_indices.len() != ncorners {
+        return Err("model is missing normals".into());
+    }
+    if model
+        .face_vertex_indices
+        .iter()
+        .any(|&i| i >= model.vertices.len())
+        || model
+            .face_texture_coordinate_indices
+            .iter()
+            .any(|&i| i >= model.texture_coordinates.len())
+        || model
+            .face_vertex_normal_indices
+            .iter()
+            .any(|&i| i >= model.vertex_normals.len())
+    {
+        return Err("model has faces with out of range indices".into());
     }
+    Ok(())
+}
 
-    fn vertex(&mut self, face_index: usize, nth_vertex: usize) -> Vec4f32 {
-        &self.model_view_projection * &self.model.unwrap().get_vertex(face_index, nth_vertex)
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("render-bin: {}", e);
+            eprintln!("try 'render-bin --help' for more information");
+            process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", cli::USAGE);
+        return;
     }
 
-    fn fragment(&mut self, _barycentric: &Vec3f32, _color: &mut TextureColor) -> bool {
-        true
+    if let Err(e) = run(&options) {
+        eprintln!("render-bin: {}", e);
+        process::exit(1);
     }
+}
 
-    fn run_once(&mut self, zbuffer: &mut Vec<f32>, frame: &mut Texture) {
-        for i in 0..self.model.unwrap().get_nfaces() {
-            let mut clip_triangle = [Vector::<f32, 4>::new(); 3];
+fn run(options: &Options) -> utils::Result<()> {
+    let input = options
+        .input
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SCENE));
+    let is_model = input
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("obj"));
+    let mut scene_file = if is_model {
+        load_model_scene(&input)?
+    } else {
+        scene::file::read_from_file(&input)?
+    };
+
+    let settings = &mut scene_file.settings;
+    if let Some(output) = &options.output {
+        settings.output = output.clone();
+    }
+    if let Some((width, height)) = options.resolution {
+        (settings.width, settings.height) = (width, height);
+    }
+    if let Some((width, height)) = options.shadow_resolution {
+        (settings.shadow_width, settings.shadow_height) = (width, height);
+    }
+    if let Some(camera) = &options.camera {
+        settings.camera = Some(camera.clone());
+    }
+    settings.shadows = options.shadows.unwrap_or(settings.shadows);
+    settings.ambient_occlusion = options
+        .ambient_occlusion
+        .unwrap_or(settings.ambient_occlusion);
+    settings.antialiasing = options.antialiasing.unwrap_or(settings.antialiasing);
+    settings.shading = options.shading.unwrap_or(settings.shading);
+
+    let format = match options.format {
+        Some(format) => format,
+        None => settings
+            .output
+            .extension()
+            .and_then(|extension| Format::from_name(&extension.to_string_lossy()))
+            .ok_or(format!(
+                "cannot tell the format of {}, use --format",
+                settings.output.display()
+            ))?,
+    };
+
+    let camera = scene_file.get_camera()?;
+    let aspect = scene_file.settings.width as f32 / scene_file.settings.height as f32;
+    set_camera(&mut scene_file.scene, camera, options, aspect)?;
+    let shadow_light = scene_file.get_shadow_light();
+
+    let animation = match options.turntable {
+        Some(frames) => Some(Animation {
+            frames,
+            path: CameraPath::Turntable {
+                center: options.center.unwrap_or_default(),
+                axis: options
+                    .up
+                    .unwrap_or(Vec3f32::new_from_array([0f32, 1f32, 0f32])),
+            },
+        }),
+        None => scene_file.animation.clone(),
+    };
+    let animation = match (animation, options.frames) {
+        (Some(animation), Some(frames)) => Some(Animation {
+            frames,
+            ..animation
+        }),
+        (None, Some(_)) => return Err("the scene has no animation, use --turntable".into()),
+        (animation, None) => animation,
+    };
+
+    let SceneFile {
+        mut scene,
+        settings,
+        ..
+    } = scene_file;
+    for draw in scene.get_draws(camera)? {
+        check_model(&draw.mesh.model)
+            .map_err(|e| format!("node {}: {}", scene.get_node(draw.node).name, e))?;
+    }
 
-            for j in 0..3 {
-                clip_triangle[j] = self.vertex(i, j);
+    let output = &settings.output;
+    let mut sink: Box<dyn FrameSink> = match format {
+        Format::Tga | Format::Png | Format::Bmp | Format::Ppm | Format::Pam => {
+            Box::new(ImageSink {
+                format,
+                output: output.clone(),
+                frames: animation.as_ref().map(|animation| animation.frames),
+                next: 0,
+            })
+        }
+        Format::Y4m => Box::new(
+            Y4mWriter::create(
+                output,
+                settings.width,
+                settings.height,
+                options.frame_rate.unwrap_or(DEFAULT_FRAME_RATE),
+                options.chroma.unwrap_or_default(),
+            )
+            .map_err(|e| format!("{}: {}", output.display(), e))?,
+        ),
+        Format::Gif => {
+            let mut gif = GifWriter::create(output, settings.width, settings.height)
+                .map_err(|e| format!("{}: {}", output.display(), e))?;
+            // gifs count time in hundredths of a second
+            let (numerator, denominator) = options.frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+            let delay = (100f64 * denominator as f64 / numerator as f64).round();
+            gif.set_delay(delay.clamp(1f64, u16::MAX as f64) as u16);
+            gif.set_dithering(options.dithering.unwrap_or(true));
+            // the loop extension counts the repetitions after the first time
+            gif.set_loop_count(match options.loop_count {
+                None | Some(0) => Some(0),
+                Some(1) => None,
+                Some(count) => Some(count - 1),
+            });
+            Box::new(gif)
+        }
+    };
+
+    match &animation {
+        Some(animation) => {
+            let start = scene.get_node(camera).transform;
+            for frame in 0..animation.frames {
+                scene.get_node_mut(camera).transform =
+                    animation.get_camera_transform(&start, frame);
+                let image = render_frame(&scene, &settings, camera, shadow_light)?;
+                sink.write_frame(&image)?;
+                // numbered images are listed as they are written
+                if !format.is_video() {
+                    println!(
+                        "{}",
+                        get_frame_path(output, frame, animation.frames).display()
+                    );
+                }
             }
-
-            render::triangle_rasterize(&clip_triangle, self, zbuffer, frame);
         }
+        None => sink.write_frame(&render_frame(&scene, &settings, camera, shadow_light)?)?,
     }
+    sink.finish()
+        .map_err(|e| format!("{}: {}", output.display(), e).into())
 }
 
-fn main() {
-    let mut frame = texture::Texture::new(WIDTH, HEIGHT);
-    let mut shadow_frame = texture::Texture::new(WIDTH_SHADOW, HEIGHT_SHADOW);
-    let mut zbuffer = vec![f32::MAX; (WIDTH * HEIGHT) as usize];
-    let mut shadow_zbuffer = vec![f32::MAX; (WIDTH_SHADOW * HEIGHT_SHADOW) as usize];
-
-    let eye: Vec4f32 = Vector::new_from_vec(&vec![1f32, 1f32, 3f32, 1f32]);
-    let center: Vec4f32 = Vector::new_from_vec(&vec![0f32, 0f32, 0f32, 1f32]);
-    let up: Vec4f32 = Vector::new_from_vec(&vec![0f32, 1f32, 0f32, 0f32]);
-    let light: Vec4f32 = Vector::new_from_vec(&vec![1f32, 2f32, 1f32, 1f32]);
-
-    let model_view: Matrix<f32, 4, 4> = render::lookat(eye, center, up);
-    let projection: Matrix<f32, 4, 4> = render::projection_pinhole((&center - &eye).norm_l2());
-    let viewport: Matrix<f32, 4, 4> =
-        render::viewport(WIDTH / 8, HEIGHT / 8, WIDTH * 3 / 4, HEIGHT * 3 / 4);
-    let mut shader = AShader::new(model_view, projection, viewport, &light - &center);
+// Writes frames to image files, numbered when there is more than one.
+struct ImageSink {
+    format: Format,
+    output: PathBuf,
+    frames: Option<usize>,
+    next: usize,
+}
 
-    let model_view_light: Matrix<f32, 4, 4> = render::lookat(light, center, up);
-    let projection_light: Matrix<f32, 4, 4> =
-        render::projection_pinhole((&center - &light).norm_l2());
+impl FrameSink for ImageSink {
+    fn write_frame(&mut self, frame: &Texture) -> utils::Result<()> {
+        let path = match self.frames {
+            Some(frames) => get_frame_path(&self.output, self.next, frames),
+            None => self.output.clone(),
+        };
+        write_image(self.format, frame, &path)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> utils::Result<()> {
+        Ok(())
+    }
+}
+
+// `<stem>_<frame>.<extension>` next to `output`, with frames numbered from 0
+// and zero-padded to the same width.
+fn get_frame_path(output: &Path, frame: usize, frames: usize) -> PathBuf {
+    let digits = (frames - 1).to_string().len().max(4);
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(extension) => format!(
+            "{}_{:0digits$}.{}",
+            stem,
+            frame,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}_{:0digits$}", stem, frame),
+    };
+    output.with_file_name(name)
+}
+
+fn write_image(format: Format, image: &Texture, path: &Path) -> utils::Result<()> {
+    match format {
+        Format::Tga => texture::tga::write_to_file(image, path, true),
+        Format::Png => texture::png::write_to_file(image, path, true),
+        Format::Bmp => texture::bmp::write_to_file(image, path, true),
+        Format::Ppm => texture::netpbm::write_to_file(image, path, netpbm::Format::Ppm, 255),
+        Format::Pam => texture::netpbm::write_to_file(image, path, netpbm::Format::Pam, 255),
+        Format::Y4m | Format::Gif => unreachable!("not an image format"),
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+fn render_frame(
+    scene: &Scene,
+    settings: &RenderSettings,
+    camera: usize,
+    shadow_light: Option<usize>,
+) -> utils::Result<Texture> {
+    let (width, height) = (settings.width, settings.height);
+    let (shadow_width, shadow_height) = (settings.shadow_width, settings.shadow_height);
+
+    let mut framebuffer = if settings.shadows == Shadows::Volumes {
+        Framebuffer::new_with_stencil(width, height)
+    } else {
+        Framebuffer::new(width, height)
+    };
+    if settings.antialiasing {
+        framebuffer.set_samples(4)?;
+    }
+    let mut shadow_framebuffer = Framebuffer::new(shadow_width, shadow_height);
+    let mut pipeline = Pipeline::new();
+
+    let viewport: Matrix<f32, 4, 4> =
+        render::viewport(width / 8, height / 8, width * 3 / 4, height * 3 / 4);
     let viewport_light: Matrix<f32, 4, 4> = render::viewport(
-        WIDTH_SHADOW / 8,
-        HEIGHT_SHADOW / 8,
-        WIDTH_SHADOW * 3 / 4,
-        HEIGHT_SHADOW * 3 / 4,
-    );
-    let mut shadow_shader = ShadowShader::new(model_view_light, projection_light, viewport_light);
-
-    let model_floor = model::Model::new_from_file(Path::new("obj/floor.obj")).unwrap();
-    let normal_map_floor =
-        texture::tga::read_from_file(Path::new("obj/floor_nm_tangent.tga")).unwrap();
-    let diffuse_map_floor =
-        texture::tga::read_from_file(Path::new("obj/floor_diffuse.tga")).unwrap();
-
-    let model =
-        model::Model::new_from_file(Path::new("obj/diablo3_pose/diablo3_pose.obj")).unwrap();
-    let normal_map =
-        texture::tga::read_from_file(Path::new("obj/diablo3_pose/diablo3_pose_nm_tangent.tga"))
-            .unwrap();
-    let diffuse_map =
-        texture::tga::read_from_file(Path::new("obj/diablo3_pose/diablo3_pose_diffuse.tga"))
-            .unwrap();
-    let specular_map =
-        texture::tga::read_from_file(Path::new("obj/diablo3_pose/diablo3_pose_spec.tga")).unwrap();
-
-    // let body_model = model::Model::new_from_file(Path::new("obj/boggie/body.obj")).unwrap();
-    // let body_normal_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/body_nm_tangent.tga")).unwrap();
-    // let body_diffuse_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/body_diffuse.tga")).unwrap();
-    // let body_specular_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/body_spec.tga")).unwrap();
-
-    // let eyes_model = model::Model::new_from_file(Path::new("obj/boggie/eyes.obj")).unwrap();
-    // let eyes_normal_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/eyes_nm_tangent.tga")).unwrap();
-    // let eyes_diffuse_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/eyes_diffuse.tga")).unwrap();
-    // let eyes_specular_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/eyes_spec.tga")).unwrap();
-
-    // let head_model = model::Model::new_from_file(Path::new("obj/boggie/head.obj")).unwrap();
-    // let head_normal_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/head_nm_tangent.tga")).unwrap();
-    // let head_diffuse_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/head_diffuse.tga")).unwrap();
-    // let head_specular_map =
-    //     texture::tga::read_from_file(Path::new("obj/boggie/head_spec.tga")).unwrap();
-
-    shadow_shader.set_model(&model_floor);
-    shadow_shader.run_once(&mut shadow_zbuffer, &mut shadow_frame);
-    shadow_shader.set_model(&model);
-    shadow_shader.run_once(&mut shadow_zbuffer, &mut shadow_frame);
-    // shadow_shader.set_model(&body_model);
-    // shadow_shader.run_once(&mut shadow_zbuffer, &mut shadow_frame);
-    // shadow_shader.set_model(&eyes_model);
-    // shadow_shader.run_once(&mut shadow_zbuffer, &mut shadow_frame);
-    // shadow_shader.set_model(&head_model);
-    // shadow_shader.run_once(&mut shadow_zbuffer, &mut shadow_frame);
-
-    shader.set_shadow_zbuffer(&shadow_zbuffer);
-    shader.set_shadow_matrix(
-        &(&(&viewport_light * &projection_light) * &model_view_light) * &model_view.inv(),
+        shadow_width / 8,
+        shadow_height / 8,
+        shadow_width * 3 / 4,
+        shadow_height * 3 / 4,
     );
 
-    shader.set_model(&model_floor);
-    shader.set_normal_map(Some(&normal_map_floor));
-    shader.set_diffuse_map(Some(&diffuse_map_floor));
-    shader.set_specular_map(None);
-    shader.run_once(&mut zbuffer, &mut frame);
-
-    shader.set_model(&model);
-    shader.set_normal_map(Some(&normal_map));
-    shader.set_diffuse_map(Some(&diffuse_map));
-    shader.set_specular_map(Some(&specular_map));
-    shader.run_once(&mut zbuffer, &mut frame);
-
-    // shader.set_model(&body_model);
-    // shader.set_normal_map(Some(&body_normal_map));
-    // shader.set_diffuse_map(Some(&body_diffuse_map));
-    // shader.set_specular_map(Some(&body_specular_map));
-    // shader.run_once(&mut zbuffer, &mut frame);
-
-    // shader.set_model(&eyes_model);
-    // shader.set_normal_map(Some(&eyes_normal_map));
-    // shader.set_diffuse_map(Some(&eyes_diffuse_map));
-    // shader.set_specular_map(Some(&eyes_specular_map));
-    // shader.run_once(&mut zbuffer, &mut frame);
-
-    // shader.set_model(&head_model);
-    // shader.set_normal_map(Some(&head_normal_map));
-    // shader.set_diffuse_map(Some(&head_diffuse_map));
-    // shader.set_specular_map(Some(&head_specular_map));
-    // shader.run_once(&mut zbuffer, &mut frame);
-
-    shader.ao(&zbuffer, &mut frame);
-
-    let frame = shader.ssaa(&frame);
-
-    texture::tga::write_to_file(&frame, Path::new("result.tga"), true).unwrap();
+    let lights = scene.get_lights();
+    let light = lights.first().ok_or("scene has no light")?.position;
+    let world = scene.get_world_transforms();
+    let projection = scene.get_node(camera).camera.unwrap().get_projection();
+    let shader = AShader::new(world[camera].inv(), projection, light);
+
+    let shadow_matrix = match (settings.shadows, shadow_light) {
+        (Shadows::Map, Some(light_camera)) => {
+            pipeline.set_viewport(viewport_light);
+            // the depth pass only needs positions, so vertices can be shared
+            for draw in scene.get_draws(light_camera)? {
+                if !scene.get_node(draw.node).cast_shadows {
+                    continue;
+                }
+                pipeline.set_cull_mode(draw.material.cull_mode);
+                pipeline.draw_indexed(
+                    &draw.mesh.model,
+                    &draw.mesh.vertex_index,
+                    &ShadowShader::new(draw.model_view, draw.projection),
+                    &mut shadow_framebuffer,
+                );
+            }
+
+            let projection_light = scene
+                .get_node(light_camera)
+                .camera
+                .unwrap()
+                .get_projection();
+            Some(
+                &(&(&viewport_light * &projection_light) * &world[light_camera].inv())
+                    * &world[camera],
+            )
+        }
+        (Shadows::Map, None) => return Err("shadow map needs a light with a camera".into()),
+        _ => None,
+    };
+
+    pipeline.set_viewport(viewport);
+    scene.render(camera, &mut pipeline, &mut framebuffer, |draw| {
+        // lights are given to the shader in model space
+        let light = &draw.world.inv() * &draw.lights[0].position;
+        let mut shader = AShader::new(draw.model_view, draw.projection, light);
+        shader.set_normal_map(draw.material.normal_map.as_ref());
+        shader.set_diffuse_map(draw.material.diffuse_map.as_ref());
+        shader.set_specular_map(draw.material.specular_map.as_ref());
+        shader.set_shading(settings.shading);
+        if let Some(shadow_matrix) = shadow_matrix {
+            shader.set_shadow_zbuffer(
+                shadow_framebuffer.get_depth_attachment(),
+                shadow_width,
+                shadow_height,
+            );
+            shader.set_shadow_matrix(shadow_matrix);
+        }
+        shader
+    })?;
+
+    if settings.shadows == Shadows::Volumes {
+        for draw in scene.get_draws(camera)? {
+            if !scene.get_node(draw.node).cast_shadows {
+                continue;
+            }
+            let model = &draw.mesh.model;
+            let light = &draw.world.inv() * &light;
+            let volume = ShadowVolume::new(model, &model.get_adjacency(), &light);
+            let volume_shader = ShadowVolumeShader::new(draw.model_view, draw.projection);
+            shadow_volume::draw_shadow_volume(&pipeline, &volume, &volume_shader, &mut framebuffer);
+        }
+        shadow_volume::darken_shadowed(&mut framebuffer, 0.4);
+    }
+
+    framebuffer.resolve();
+    if settings.ambient_occlusion {
+        shader.ao(&mut framebuffer);
+    }
+    Ok(framebuffer.into_color_attachment())
 }
+
+mod cli;
@@ -7,12 +7,12 @@ pub struct Matrix<T, const N_ROW: usize, const N_COL: usize>([Vector<T, N_COL>;
 where
     T: Default + Debug + Copy + SimdElement;
 
-type Matrix2f32 = Matrix<f32, 2, 2>;
-type Matrix2f64 = Matrix<f64, 2, 2>;
-type Matrix3f32 = Matrix<f32, 3, 3>;
-type Matrix3f64 = Matrix<f64, 3, 3>;
-type Matrix4f32 = Matrix<f32, 4, 4>;
-type Matrix4f64 = Matrix<f64, 4, 4>;
+pub type Matrix2f32 = Matrix<f32, 2, 2>;
+pub type Matrix2f64 = Matrix<f64, 2, 2>;
+pub type Matrix3f32 = Matrix<f32, 3, 3>;
+pub type Matrix3f64 = Matrix<f64, 3, 3>;
+pub type Matrix4f32 = Matrix<f32, 4, 4>;
+pub type Matrix4f64 = Matrix<f64, 4, 4>;
 
 impl ops::Add for &Matrix4f32 {
     type Output = Matrix4f32;
@@ -156,7 +156,7 @@ impl Matrix4f32 {
     pub fn det(&self) -> f32 {
         let mut res = Default::default();
         for j in 0..4 {
-            res = res + self[0][j] * self.cofactor(0, j);
+            res += self[0][j] * self.cofactor(0, j);
         }
         res
     }
@@ -458,7 +458,7 @@ where
 
     #[inline(always)]
     fn index(&self, index: usize) -> &Self::Output {
-        return &self.0[index];
+        &self.0[index]
     }
 }
 
@@ -468,7 +468,17 @@ where
 {
     #[inline(always)]
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        return &mut self.0[index];
+        &mut self.0[index]
+    }
+}
+
+impl<T, const N_ROW: usize, const N_COL: usize> Default for Matrix<T, N_ROW, N_COL>
+where
+    T: Default + Debug + Copy + SimdElement,
+{
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
     }
 }
 
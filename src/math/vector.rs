@@ -42,7 +42,7 @@ impl ops::Mul for &Vec4f32 {
     #[inline(always)]
     fn mul(self, rhs: Self) -> Self::Output {
         let (a, b) = (Simd::from(self.0), Simd::from(rhs.0));
-        Simd::from(a * b).reduce_sum()
+        (a * b).reduce_sum()
     }
 }
 
@@ -116,7 +116,7 @@ impl ops::Mul for &Vec2f32 {
     #[inline(always)]
     fn mul(self, rhs: Self) -> Self::Output {
         let (a, b) = (Simd::from(self.0), Simd::from(rhs.0));
-        Simd::from(a * b).reduce_sum()
+        (a * b).reduce_sum()
     }
 }
 
@@ -161,7 +161,7 @@ impl ops::Add for &Vec3f32 {
             Simd::from(self.embed::<4>(0f32).0),
             Simd::from(rhs.embed::<4>(0f32).0),
         );
-        Vec3f32::new_from_vec(&(a + b).to_array().to_vec())
+        Vec3f32::new_from_vec(&(a + b).to_array())
     }
 }
 
@@ -174,7 +174,7 @@ impl ops::Sub for &Vec3f32 {
             Simd::from(self.embed::<4>(0f32).0),
             Simd::from(rhs.embed::<4>(0f32).0),
         );
-        Vec3f32::new_from_vec(&(a - b).to_array().to_vec())
+        Vec3f32::new_from_vec(&(a - b).to_array())
     }
 }
 
@@ -187,7 +187,7 @@ impl ops::Mul for &Vec3f32 {
             Simd::from(self.embed::<4>(0f32).0),
             Simd::from(rhs.embed::<4>(0f32).0),
         );
-        Simd::from(a * b).reduce_sum()
+        (a * b).reduce_sum()
     }
 }
 
@@ -197,7 +197,7 @@ impl ops::Mul<f32> for &Vec3f32 {
     #[inline(always)]
     fn mul(self, rhs: f32) -> Self::Output {
         let (a, b) = (Simd::from(self.embed::<4>(0f32).0), Simd::splat(rhs));
-        Vec3f32::new_from_vec(&(a * b).to_array().to_vec())
+        Vec3f32::new_from_vec(&(a * b).to_array())
     }
 }
 
@@ -207,7 +207,7 @@ impl ops::Div<f32> for &Vec3f32 {
     #[inline(always)]
     fn div(self, rhs: f32) -> Self::Output {
         let (a, b) = (Simd::from(self.embed::<4>(0f32).0), Simd::splat(rhs));
-        Vec3f32::new_from_vec(&(a / b).to_array().to_vec())
+        Vec3f32::new_from_vec(&(a / b).to_array())
     }
 }
 
@@ -242,7 +242,7 @@ where
 
     #[inline(always)]
     fn index(&self, index: usize) -> &Self::Output {
-        return &self.0[index];
+        &self.0[index]
     }
 }
 
@@ -252,7 +252,17 @@ where
 {
     #[inline(always)]
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        return &mut self.0[index];
+        &mut self.0[index]
+    }
+}
+
+impl<T, const N: usize> Default for Vector<T, N>
+where
+    T: Default + Debug + Copy + SimdElement,
+{
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -266,15 +276,8 @@ where
     }
 
     #[inline(always)]
-    pub fn new_from_vec(src: &Vec<T>) -> Self {
-        let inner: [T; N] = src
-            .into_iter()
-            .take(N)
-            .map(|&a| a)
-            .collect::<Vec<T>>()
-            .try_into()
-            .unwrap();
-        Self(inner)
+    pub fn new_from_vec(src: &[T]) -> Self {
+        Self(src[..N].try_into().unwrap())
     }
 
     #[inline(always)]
@@ -1,5 +1,6 @@
 use core::str::{FromStr, SplitAsciiWhitespace};
 use std::{
+    collections::HashMap,
     fs::File,
     io::{BufRead, BufReader},
     path::Path,
@@ -11,7 +12,19 @@ use crate::{
     utils,
 };
 
-#[derive(Debug)]
+/// Index buffer over the distinct vertices of a model, for
+/// `Pipeline::draw_indexed`. Face corners referring to the same position,
+/// texture coordinate and normal share a vertex.
+#[derive(Debug, Clone, Default)]
+pub struct VertexIndex {
+    /// The first face corner, as `(face_index, nth_vertex)`, of every distinct
+    /// vertex.
+    pub vertices: Vec<(usize, usize)>,
+    /// The distinct vertex of every face corner, three per face.
+    pub indices: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Model {
     pub vertices: Vec<Vec4f32>,
     pub texture_coordinates: Vec<Vec2f32>,
@@ -21,25 +34,26 @@ pub struct Model {
     pub face_vertex_normal_indices: Vec<usize>,
 }
 
+impl Default for Model {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Model {
     fn parse_vertex(&mut self, iter: &mut SplitAsciiWhitespace) -> utils::Result<()> {
         let raw_data: Vec<f32> = iter
             .map(FromStr::from_str)
             .collect::<Result<Vec<f32>, _>>()?;
 
+        // x y z with an optional w, or followed by an r g b vertex color
         match raw_data.len() {
-            _ => {
+            3 | 4 | 6 => {
                 self.vertices
                     .push(Vec3f32::new_from_vec(&raw_data).embed(1f32));
                 Ok(())
             }
-            // 4 => {
-            //     let tmp_vector = Vec4f32::new_from_vec(&raw_data);
-            //     let tmp_vector = &tmp_vector / tmp_vector[3];
-            //     self.vertices.push(tmp_vector);
-            //     Ok(())
-            // }
-            // _ => Err("obj vertex parse fail".into()),
+            _ => Err("obj vertex parse fail".into()),
         }
     }
 
@@ -83,21 +97,21 @@ impl Model {
                     match single_raw_data.len() {
                         1 => {
                             self.face_vertex_indices
-                                .push(single_raw_data[0].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[0])?);
                         }
                         2 => {
                             self.face_vertex_indices
-                                .push(single_raw_data[0].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[0])?);
                             self.face_texture_coordinate_indices
-                                .push(single_raw_data[1].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[1])?);
                         }
                         3 => {
                             self.face_vertex_indices
-                                .push(single_raw_data[0].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[0])?);
                             self.face_texture_coordinate_indices
-                                .push(single_raw_data[1].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[1])?);
                             self.face_vertex_normal_indices
-                                .push(single_raw_data[2].parse::<usize>()? - 1);
+                                .push(parse_index(single_raw_data[2])?);
                         }
                         _ => {
                             return Err("obj face parse fail".into());
@@ -122,8 +136,10 @@ impl Model {
     }
 
     pub fn new_from_file(path: &Path) -> utils::Result<Model> {
-        let file = File::open(&path)?;
-        let mut reader = BufReader::new(file);
+        Self::new_from_reader(BufReader::new(File::open(path)?))
+    }
+
+    fn new_from_reader<R: BufRead>(mut reader: R) -> utils::Result<Model> {
         let mut new_model: Model = Model::new();
 
         let mut buffer = String::new();
@@ -168,4 +184,119 @@ impl Model {
     pub fn get_vertex(&self, face_index: usize, nth_vertex: usize) -> Vec4f32 {
         self.vertices[self.face_vertex_indices[face_index * 3 + nth_vertex]]
     }
+
+    /// For every face, the neighbouring faces across its edges, the `nth`
+    /// entry being the face sharing the edge from vertex `nth` to vertex
+    /// `(nth + 1) % 3`. Faces are connected through shared vertex indices;
+    /// edges on the border of the mesh, or shared by more than two faces,
+    /// have no neighbour.
+    pub fn get_adjacency(&self) -> Vec<[Option<usize>; 3]> {
+        let nfaces = self.get_nfaces();
+        let edge = |face_index: usize, nth: usize| {
+            let a = self.face_vertex_indices[face_index * 3 + nth];
+            let b = self.face_vertex_indices[face_index * 3 + (nth + 1) % 3];
+            (a.min(b), a.max(b))
+        };
+
+        let mut edges: HashMap<(usize, usize), Vec<(usize, usize)>> = HashMap::new();
+        for face_index in 0..nfaces {
+            for nth in 0..3 {
+                edges
+                    .entry(edge(face_index, nth))
+                    .or_default()
+                    .push((face_index, nth));
+            }
+        }
+
+        let mut adjacency = vec![[None; 3]; nfaces];
+        for faces in edges.values() {
+            if let [(face_a, nth_a), (face_b, nth_b)] = faces[..] {
+                adjacency[face_a][nth_a] = Some(face_b);
+                adjacency[face_b][nth_b] = Some(face_a);
+            }
+        }
+        adjacency
+    }
+
+    pub fn get_vertex_index(&self) -> VertexIndex {
+        let mut index = VertexIndex::default();
+        let mut distinct: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+        for corner in 0..self.get_nfaces() * 3 {
+            let key = (
+                self.face_vertex_indices[corner],
+                self.face_texture_coordinate_indices.get(corner).copied(),
+                self.face_vertex_normal_indices.get(corner).copied(),
+            );
+            let vertex = *distinct.entry(key).or_insert_with(|| {
+                index.vertices.push((corner / 3, corner % 3));
+                index.vertices.len() - 1
+            });
+            index.indices.push(vertex);
+        }
+        index
+    }
+}
+
+/// Parses a one-based OBJ index into a zero-based one.
+fn parse_index(index: &str) -> utils::Result<usize> {
+    match index.parse::<usize>() {
+        Ok(index) if index > 0 => Ok(index - 1),
+        _ => Err("obj face parse fail".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> utils::Result<Model> {
+        Model::new_from_reader(text.as_bytes())
+    }
+
+    #[test]
+    fn parse_obj() {
+        let model = parse(
+            "# a quad
+v 0 0 0
+v 1 0 0 1
+v 1 1 0 0.5 0.5 0.5
+v 0 1 0
+vt 0 0
+vt 1 1 0
+vn 0 0 1
+f 1/1/1 2/1/1 3/2/1
+f 1 3 4
+",
+        )
+        .unwrap();
+        assert_eq!(model.vertices.len(), 4);
+        assert_eq!(model.get_nfaces(), 2);
+        let vertex = model.vertices[2];
+        assert_eq!([0, 1, 2, 3].map(|i| vertex[i]), [1f32, 1f32, 0f32, 1f32]);
+        let uv = model.get_uv(0, 2);
+        assert_eq!([uv[0], uv[1]], [1f32, 0f32]);
+        let normal = model.get_normal(0, 1);
+        assert_eq!([0, 1, 2, 3].map(|i| normal[i]), [0f32, 0f32, 1f32, 0f32]);
+        assert_eq!(model.face_vertex_indices, [0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn malformed_obj() {
+        for text in [
+            "v 1 2",
+            "v 1 2 3 4 5",
+            "v 1 2 z",
+            "vt 1",
+            "vt 1 2 3 4",
+            "vn 0 1",
+            "vn 0 0 1 0",
+            "f 1 2",
+            "f 1 2 3 4",
+            "f 0 1 2",
+            "f 1/1/1/1 2 3",
+            "f -1 2 3",
+        ] {
+            assert!(parse(text).is_err(), "{:?}", text);
+        }
+    }
 }
@@ -1,15 +1,288 @@
+use std::{sync::Mutex, thread};
+
+use self::{blend::BlendState, stencil::StencilState};
 use crate::{
-    math::{matrix::Matrix, vector::Vec2f32, vector::Vec3f32, vector::Vec4f32},
-    texture::{Texture, TextureColor},
+    frame::Framebuffer,
+    math::{
+        matrix::Matrix,
+        vector::{Vec2f32, Vec3f32, Vec4f32, Vector},
+    },
+    model::{Model, VertexIndex},
+    texture::TextureColor,
 };
 
+/// The programmable stages of a draw call. Everything else about how
+/// primitives end up in the framebuffer is state of the `Pipeline`.
 pub trait Shader {
-    fn get_model_view(&self) -> &Matrix<f32, 4, 4>;
-    fn get_projection(&self) -> &Matrix<f32, 4, 4>;
-    fn get_viewport(&self) -> &Matrix<f32, 4, 4>;
-    fn vertex(&mut self, face_index: usize, nth_vertex: usize) -> Vec4f32;
-    fn fragment(&mut self, barycentric: &Vec3f32, color: &mut TextureColor) -> bool;
-    fn run_once(&mut self, zbuffer: &mut Vec<f32>, frame: &mut Texture);
+    /// Per-vertex outputs of `vertex`, interpolated across the primitive for
+    /// `fragment`.
+    type Varyings: Varyings;
+
+    /// Returns the clip-space position of a vertex of `model` together with
+    /// its varyings.
+    fn vertex(
+        &mut self,
+        model: &Model,
+        face_index: usize,
+        nth_vertex: usize,
+    ) -> (Vec4f32, Self::Varyings);
+    fn fragment(&mut self, varyings: &Self::Varyings, color: &mut TextureColor) -> bool;
+    /// Shades a fragment of a point sprite. `point_coord` runs from 0 to 1
+    /// across the sprite, starting at its bottom-left corner.
+    fn point_fragment(
+        &mut self,
+        varyings: &Self::Varyings,
+        _point_coord: &Vec2f32,
+        color: &mut TextureColor,
+    ) -> bool {
+        self.fragment(varyings, color)
+    }
+    /// Called by `Pipeline::draw_instanced` before the vertices of instance
+    /// `instance_id` are transformed; the faces rasterized until the next
+    /// call all belong to that instance.
+    fn set_instance(&mut self, _instance_id: usize, _instance: &Instance) {}
+}
+
+/// Per-instance data of `Pipeline::draw_instanced`, which it is up to the
+/// shader to apply.
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    /// Model-space transform of the instance.
+    pub transform: Matrix<f32, 4, 4>,
+    /// Color to multiply the instance's fragments by, white leaving them
+    /// unchanged.
+    pub tint: TextureColor,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Instance::new(identity())
+    }
+}
+
+impl Instance {
+    pub fn new(transform: Matrix<f32, 4, 4>) -> Self {
+        Instance {
+            transform,
+            tint: TextureColor {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+        }
+    }
+}
+
+/// Values a vertex shader hands to the fragment shader. The rasterizer
+/// interpolates them perspective correct, which only ever takes weighted sums
+/// of the values at the vertices of the original, unclipped triangle.
+pub trait Varyings: Clone {
+    /// `values[0] * weights[0] + values[1] * weights[1] + values[2] * weights[2]`,
+    /// the weights summing to one.
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self;
+}
+
+impl Varyings for () {
+    fn interpolate(_values: &[Self; 3], _weights: &Vec3f32) -> Self {}
+}
+
+impl Varyings for f32 {
+    #[inline(always)]
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self {
+        values[0] * weights[0] + values[1] * weights[1] + values[2] * weights[2]
+    }
+}
+
+impl<const N: usize> Varyings for Vector<f32, N> {
+    #[inline(always)]
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self {
+        let mut result = Vector::new();
+        for i in 0..N {
+            result[i] = f32::interpolate(&[values[0][i], values[1][i], values[2][i]], weights);
+        }
+        result
+    }
+}
+
+impl<A: Varyings, B: Varyings> Varyings for (A, B) {
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self {
+        (
+            A::interpolate(&values.clone().map(|value| value.0), weights),
+            B::interpolate(&values.clone().map(|value| value.1), weights),
+        )
+    }
+}
+
+impl<A: Varyings, B: Varyings, C: Varyings> Varyings for (A, B, C) {
+    fn interpolate(values: &[Self; 3], weights: &Vec3f32) -> Self {
+        (
+            A::interpolate(&values.clone().map(|value| value.0), weights),
+            B::interpolate(&values.clone().map(|value| value.1), weights),
+            C::interpolate(&values.clone().map(|value| value.2), weights),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CullMode {
+    #[default]
+    None,
+    Back,
+    Front,
+}
+
+/// Screen-space winding of front-facing triangles, with the y axis pointing
+/// up as produced by `viewport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrontFace {
+    #[default]
+    CounterClockwise,
+    Clockwise,
+}
+
+/// Comparison of a fragment's value against the one stored in the
+/// framebuffer, passing when `value <op> stored` holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    Equal,
+    #[default]
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunc {
+    #[inline(always)]
+    pub fn test<T: PartialOrd>(self, value: T, stored: T) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < stored,
+            CompareFunc::Equal => value == stored,
+            CompareFunc::LessEqual => value <= stored,
+            CompareFunc::Greater => value > stored,
+            CompareFunc::NotEqual => value != stored,
+            CompareFunc::GreaterEqual => value >= stored,
+            CompareFunc::Always => true,
+        }
+    }
+
+    // the same test with the order of depth values reversed
+    fn mirrored(self) -> Self {
+        match self {
+            CompareFunc::Less => CompareFunc::Greater,
+            CompareFunc::LessEqual => CompareFunc::GreaterEqual,
+            CompareFunc::Greater => CompareFunc::Less,
+            CompareFunc::GreaterEqual => CompareFunc::LessEqual,
+            other => other,
+        }
+    }
+}
+
+/// How triangles are rasterized: filled, as their three edges (wireframe) or
+/// as point sprites at their three vertices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolygonMode {
+    #[default]
+    Fill,
+    Line,
+    Point,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipelineStats {
+    pub triangles: usize,
+    pub culled: usize,
+    pub clipped: usize,
+    pub outside: usize,
+    pub lines: usize,
+    pub points: usize,
+    /// Runs of `Shader::vertex` made by draw calls.
+    pub vertex_invocations: usize,
+    /// Face corners of indexed draws that reused an already transformed
+    /// vertex.
+    pub vertex_cache_hits: usize,
+}
+
+/// Fractional bits kept when snapping screen-space vertices to the fixed-point
+/// grid the rasterizer works on.
+pub const SUBPIXEL_BITS: u32 = 8;
+
+/// Edge length in pixels of the square screen tiles used by `draw`.
+pub const TILE_SIZE: u32 = 64;
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    viewport: Matrix<f32, 4, 4>,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    polygon_mode: PolygonMode,
+    point_size: f32,
+    // moves fragments towards the camera in depth buffer units
+    depth_bias: f32,
+    blend_state: BlendState,
+    depth_test: bool,
+    depth_compare: CompareFunc,
+    depth_write: bool,
+    stencil_test: bool,
+    stencil_state: StencilState,
+    color_write: bool,
+    // record fragments in the framebuffer's fragment lists instead of
+    // blending them
+    order_independent: bool,
+    // worker threads for `draw`, 0 meaning one per available core
+    threads: usize,
+    stats: PipelineStats,
+}
+
+pub fn identity() -> Matrix<f32, 4, 4> {
+    let mut identity = Matrix::new();
+    for i in 0..4 {
+        identity[i][i] = 1f32;
+    }
+    identity
+}
+
+pub fn translation(offset: Vec3f32) -> Matrix<f32, 4, 4> {
+    let mut translation = identity();
+    for i in 0..3 {
+        translation[i][3] = offset[i];
+    }
+    translation
+}
+
+/// Rotation by `angle` radians around `axis`, counterclockwise when looking
+/// down the axis towards the origin.
+pub fn rotation(axis: Vec3f32, angle: f32) -> Matrix<f32, 4, 4> {
+    let axis = axis.normalize();
+    let (sin, cos) = angle.sin_cos();
+
+    let mut rotation = identity();
+    for i in 0..3 {
+        for j in 0..3 {
+            rotation[i][j] = axis[i] * axis[j] * (1f32 - cos);
+        }
+        rotation[i][i] += cos;
+    }
+    rotation[0][1] -= axis[2] * sin;
+    rotation[0][2] += axis[1] * sin;
+    rotation[1][0] += axis[2] * sin;
+    rotation[1][2] -= axis[0] * sin;
+    rotation[2][0] -= axis[1] * sin;
+    rotation[2][1] += axis[0] * sin;
+    rotation
+}
+
+pub fn scaling(factors: Vec3f32) -> Matrix<f32, 4, 4> {
+    let mut scaling = identity();
+    for i in 0..3 {
+        scaling[i][i] = factors[i];
+    }
+    scaling
 }
 
 pub fn lookat(eye: Vec4f32, center: Vec4f32, up: Vec4f32) -> Matrix<f32, 4, 4> {
@@ -46,17 +319,76 @@ pub fn viewport(x: u32, y: u32, w: u32, h: u32) -> Matrix<f32, 4, 4> {
     viewport
 }
 
+/// Pinhole projection onto the plane at `focal_length`. Depth buffer values
+/// are `1 - focal_length / z`, zero on the focal plane and approaching 1 far
+/// away.
 pub fn projection_pinhole(focal_length: f32) -> Matrix<f32, 4, 4> {
     let mut projection = Matrix::<f32, 4, 4>::new();
 
     projection[0][0] = 1f32;
     projection[1][1] = 1f32;
-    projection[2][2] = 1f32;
+    (projection[2][2], projection[2][3]) = (1f32 / focal_length, -1f32);
     projection[3][2] = 1f32 / focal_length;
 
     projection
 }
 
+/// Recovers the view-space depth of a depth buffer value written through
+/// `projection_pinhole` with the same focal length.
+pub fn pinhole_view_depth(depth: f32, focal_length: f32) -> f32 {
+    focal_length / (1f32 - depth)
+}
+
+/// Recovers the view-space depth of a depth buffer value written through
+/// any of the projections of this module.
+pub fn view_depth(projection: &Matrix<f32, 4, 4>, depth: f32) -> f32 {
+    projection[2][3] / (projection[3][2] * depth - projection[2][2])
+}
+
+// Perspective projection of a vertical field of view `fov_y` (in radians)
+// with `w` the view-space depth and the depth row left to the caller.
+fn projection_frustum(fov_y: f32, aspect: f32) -> Matrix<f32, 4, 4> {
+    let focal = 1f32 / (fov_y / 2f32).tan();
+    let mut projection = Matrix::<f32, 4, 4>::new();
+
+    projection[0][0] = focal / aspect;
+    projection[1][1] = focal;
+    projection[3][2] = 1f32;
+
+    projection
+}
+
+/// Perspective projection mapping depths from `near` to `far` onto depth
+/// buffer values from 0 to 1.
+pub fn projection_perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> Matrix<f32, 4, 4> {
+    let mut projection = projection_frustum(fov_y, aspect);
+    (projection[2][2], projection[2][3]) = (far / (far - near), -near * far / (far - near));
+    projection
+}
+
+/// Like `projection_perspective`, but mapping `near` to 1 and `far` to 0 for
+/// use with reversed-Z framebuffers. The float depth buffer then has most of
+/// its precision at distance, where the 1 / z distribution of depth values
+/// has the least.
+pub fn projection_perspective_reversed(
+    fov_y: f32,
+    aspect: f32,
+    near: f32,
+    far: f32,
+) -> Matrix<f32, 4, 4> {
+    let mut projection = projection_frustum(fov_y, aspect);
+    (projection[2][2], projection[2][3]) = (-near / (far - near), near * far / (far - near));
+    projection
+}
+
+/// Reversed-Z perspective projection without a far plane: depth buffer values
+/// are `near / z`, reaching 0 at infinity.
+pub fn projection_infinite_reversed(fov_y: f32, aspect: f32, near: f32) -> Matrix<f32, 4, 4> {
+    let mut projection = projection_frustum(fov_y, aspect);
+    projection[2][3] = near;
+    projection
+}
+
 #[inline(always)]
 pub fn barycentric_coordinates(triangle: &[Vec2f32; 3], point: &Vec2f32) -> Vec3f32 {
     let a = &triangle[0];
@@ -73,115 +405,1400 @@ pub fn barycentric_coordinates(triangle: &[Vec2f32; 3], point: &Vec2f32) -> Vec3
     res
 }
 
-pub fn triangle_rasterize(
-    triangle: &[Vec4f32; 3],
-    shader: &mut dyn Shader,
-    zbuffer: &mut Vec<f32>,
-    frame: &mut Texture,
-) {
-    let screen_triangle: [Vec4f32; 3] = [
-        shader.get_viewport() * &triangle[0],
-        shader.get_viewport() * &triangle[1],
-        shader.get_viewport() * &triangle[2],
-    ];
-    let screen_triangle_perspective: [Vec2f32; 3] = [
-        (&screen_triangle[0] / screen_triangle[0][3]).project(),
-        (&screen_triangle[1] / screen_triangle[1][3]).project(),
-        (&screen_triangle[2] / screen_triangle[2][3]).project(),
-    ];
-
-    let mut bbox_min = [
-        (frame.get_width() - 1) as f32,
-        (frame.get_height() - 1) as f32,
-    ];
-    let mut bbox_max = [0f32; 2];
-    for vertex in screen_triangle_perspective {
-        bbox_min[0] = if bbox_min[0] < vertex[0].floor() {
-            bbox_min[0]
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            viewport: identity(),
+            cull_mode: Default::default(),
+            front_face: Default::default(),
+            polygon_mode: Default::default(),
+            point_size: 1f32,
+            depth_bias: 0f32,
+            blend_state: Default::default(),
+            depth_test: true,
+            depth_compare: Default::default(),
+            depth_write: true,
+            stencil_test: false,
+            stencil_state: Default::default(),
+            color_write: true,
+            order_independent: false,
+            threads: 0,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the transform from clip space to screen space, as built by
+    /// `viewport`. Defaults to the identity.
+    pub fn set_viewport(&mut self, viewport: Matrix<f32, 4, 4>) {
+        self.viewport = viewport
+    }
+
+    pub fn get_viewport(&self) -> &Matrix<f32, 4, 4> {
+        &self.viewport
+    }
+
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.cull_mode = cull_mode
+    }
+
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face
+    }
+
+    pub fn set_polygon_mode(&mut self, polygon_mode: PolygonMode) {
+        self.polygon_mode = polygon_mode
+    }
+
+    /// Edge length in pixels of the square sprites drawn for points.
+    pub fn set_point_size(&mut self, point_size: f32) {
+        self.point_size = point_size
+    }
+
+    /// Pulls fragments towards the camera by `depth_bias` (in depth buffer
+    /// units), or pushes them away for negative values. A wireframe drawn
+    /// with a small bias over the shaded model wins the depth test against
+    /// the triangles it outlines.
+    pub fn set_depth_bias(&mut self, depth_bias: f32) {
+        self.depth_bias = depth_bias
+    }
+
+    pub fn set_blend_state(&mut self, blend_state: BlendState) {
+        self.blend_state = blend_state
+    }
+
+    /// With the depth test disabled every fragment passes and depth is never
+    /// written.
+    pub fn set_depth_test(&mut self, depth_test: bool) {
+        self.depth_test = depth_test
+    }
+
+    /// Depth comparison, `LessEqual` by default. Functions are stated for
+    /// smaller depth meaning closer; on reversed-Z framebuffers they are
+    /// mirrored, so `Less` keeps passing fragments in front.
+    pub fn set_depth_compare(&mut self, depth_compare: CompareFunc) {
+        self.depth_compare = depth_compare
+    }
+
+    /// Whether fragments passing the depth test update the depth attachment.
+    /// Transparent geometry is usually drawn with depth writes turned off.
+    pub fn set_depth_write(&mut self, depth_write: bool) {
+        self.depth_write = depth_write
+    }
+
+    /// The stencil test only has an effect on framebuffers with a stencil
+    /// attachment.
+    pub fn set_stencil_test(&mut self, stencil_test: bool) {
+        self.stencil_test = stencil_test
+    }
+
+    /// Stencil fail and depth fail operations are applied as soon as a sample
+    /// is tested, the pass operation only once the fragment shader has kept
+    /// the fragment.
+    pub fn set_stencil_state(&mut self, stencil_state: StencilState) {
+        self.stencil_state = stencil_state
+    }
+
+    /// With color writes turned off, fragments only update depth and stencil,
+    /// as needed when building a stencil mask.
+    pub fn set_color_write(&mut self, color_write: bool) {
+        self.color_write = color_write
+    }
+
+    /// Order-independent transparency: fragments passing the depth test are
+    /// appended to the framebuffer's fragment lists, which must be enabled,
+    /// and composited in depth order by `Framebuffer::resolve`. The blend
+    /// state is not used and depth is never written in this mode.
+    pub fn set_order_independent(&mut self, order_independent: bool) {
+        self.order_independent = order_independent
+    }
+
+    pub fn get_stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Default::default()
+    }
+
+    // The determinant of the clip-space (x, y, w) rows has the sign of the
+    // screen-space winding for triangles in front of the camera, and stays
+    // meaningful for triangles crossing the near plane, so culling can be
+    // decided before clipping.
+    fn is_front_facing(&self, triangle: &[Vec4f32; 3]) -> bool {
+        let mut m = Matrix::<f32, 3, 3>::new();
+        for (i, vertex) in triangle.iter().enumerate() {
+            m[i] = Vec3f32::new_from_array([vertex[0], vertex[1], vertex[3]]);
+        }
+        let counter_clockwise = m.det() > 0f32;
+        match self.front_face {
+            FrontFace::CounterClockwise => counter_clockwise,
+            FrontFace::Clockwise => !counter_clockwise,
+        }
+    }
+
+    fn is_culled(&self, triangle: &[Vec4f32; 3]) -> bool {
+        if self.cull_mode == CullMode::None {
+            return false;
+        }
+
+        let front_facing = self.is_front_facing(triangle);
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing,
+        }
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads
+    }
+
+    // Culling and clipping, done once per triangle before any pixel is touched.
+    fn assemble(&self, triangle: &[Vec4f32; 3]) -> Assembly {
+        if self.is_culled(triangle) {
+            return Assembly::Culled;
+        }
+
+        match clip::clip_triangle(triangle) {
+            clip::ClipResult::Inside => Assembly::Inside,
+            clip::ClipResult::Outside => Assembly::Outside,
+            clip::ClipResult::Clipped(polygon) => Assembly::Clipped(polygon),
+        }
+    }
+
+    fn record(&mut self, assembly: &Assembly) {
+        self.stats.triangles += 1;
+        match assembly {
+            Assembly::Culled => self.stats.culled += 1,
+            Assembly::Outside => self.stats.outside += 1,
+            Assembly::Inside => {}
+            Assembly::Clipped(_) => self.stats.clipped += 1,
+        }
+    }
+
+    fn rasterize_assembly<S: Shader + ?Sized>(
+        &self,
+        triangle: &[Vec4f32; 3],
+        varyings: &[S::Varyings; 3],
+        assembly: &Assembly,
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        let front_facing = self.is_front_facing(triangle);
+        match (self.polygon_mode, assembly) {
+            (_, Assembly::Culled | Assembly::Outside) => {}
+            (PolygonMode::Line, _) => {
+                // the original edges, not those of the clipped polygon, which
+                // would outline the clipping planes as well
+                for i in 0..3 {
+                    let j = (i + 1) % 3;
+                    let line = [
+                        clip::ClipVertex {
+                            position: triangle[i],
+                            barycentric: barycentric_basis(i),
+                        },
+                        clip::ClipVertex {
+                            position: triangle[j],
+                            barycentric: barycentric_basis(j),
+                        },
+                    ];
+                    if let Some(line) = clip::clip_line(&line) {
+                        self.clipped_line_rasterize(
+                            &line,
+                            varyings,
+                            front_facing,
+                            shader,
+                            framebuffer,
+                        );
+                    }
+                }
+            }
+            (PolygonMode::Point, _) => {
+                for (vertex, vertex_varyings) in triangle.iter().zip(varyings.iter()) {
+                    self.clipped_point_rasterize(
+                        vertex,
+                        vertex_varyings,
+                        front_facing,
+                        shader,
+                        framebuffer,
+                    );
+                }
+            }
+            (PolygonMode::Fill, Assembly::Inside) => {
+                let mut identity = Matrix::<f32, 3, 3>::new();
+                (identity[0][0], identity[1][1], identity[2][2]) = (1f32, 1f32, 1f32);
+                self.clipped_triangle_rasterize(
+                    triangle,
+                    &identity,
+                    varyings,
+                    front_facing,
+                    shader,
+                    framebuffer,
+                );
+            }
+            (PolygonMode::Fill, Assembly::Clipped(polygon)) => {
+                // triangulate the convex polygon as a fan around its first vertex
+                for i in 1..polygon.len() - 1 {
+                    let (a, b, c) = (&polygon[0], &polygon[i], &polygon[i + 1]);
+
+                    let mut barycentric_map = Matrix::<f32, 3, 3>::new();
+                    barycentric_map.set_col(0, &a.barycentric);
+                    barycentric_map.set_col(1, &b.barycentric);
+                    barycentric_map.set_col(2, &c.barycentric);
+
+                    self.clipped_triangle_rasterize(
+                        &[a.position, b.position, c.position],
+                        &barycentric_map,
+                        varyings,
+                        front_facing,
+                        shader,
+                        framebuffer,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn triangle_rasterize<S: Shader + ?Sized>(
+        &mut self,
+        triangle: &[Vec4f32; 3],
+        varyings: &[S::Varyings; 3],
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        let assembly = self.assemble(triangle);
+        self.record(&assembly);
+        self.rasterize_assembly(triangle, varyings, &assembly, shader, framebuffer);
+    }
+
+    /// Draws a segment between two clip-space positions, interpolating the
+    /// varyings of its ends along it.
+    pub fn line_rasterize<S: Shader + ?Sized>(
+        &mut self,
+        line: &[Vec4f32; 2],
+        varyings: &[S::Varyings; 2],
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        self.stats.lines += 1;
+
+        let line = [
+            clip::ClipVertex {
+                position: line[0],
+                barycentric: barycentric_basis(0),
+            },
+            clip::ClipVertex {
+                position: line[1],
+                barycentric: barycentric_basis(1),
+            },
+        ];
+        // the third vertex never gets any weight
+        let varyings = [
+            varyings[0].clone(),
+            varyings[1].clone(),
+            varyings[0].clone(),
+        ];
+        if let Some(line) = clip::clip_line(&line) {
+            self.clipped_line_rasterize(&line, &varyings, true, shader, framebuffer);
+        }
+    }
+
+    /// Draws a point sprite of `point_size` pixels centered on a clip-space
+    /// position. Every fragment sees the same varyings.
+    pub fn point_rasterize<S: Shader + ?Sized>(
+        &mut self,
+        point: &Vec4f32,
+        varyings: &S::Varyings,
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        self.stats.points += 1;
+        self.clipped_point_rasterize(point, varyings, true, shader, framebuffer);
+    }
+
+    /// Draws the faces of several models sorted back to front by the average
+    /// clip-space `w` of their vertices, which is how transparent geometry has
+    /// to reach a blending pipeline. Each entry of `draws` is a model together
+    /// with the shader drawing it; faces at equal distance keep their
+    /// submission order.
+    pub fn draw_sorted<V: Varyings>(
+        &mut self,
+        draws: &mut [(&Model, &mut dyn Shader<Varyings = V>)],
+        framebuffer: &mut Framebuffer,
+    ) {
+        let mut faces = vec![];
+        for (draw_index, (model, shader)) in draws.iter_mut().enumerate() {
+            for face_index in 0..model.get_nfaces() {
+                let (triangle, varyings) = vertex_triangle(*shader, model, face_index);
+                let distance = (triangle[0][3] + triangle[1][3] + triangle[2][3]) / 3f32;
+                faces.push((distance, draw_index, triangle, varyings));
+            }
+        }
+        faces.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        self.stats.vertex_invocations += faces.len() * 3;
+        for (_, draw_index, triangle, varyings) in faces {
+            let shader = &mut draws[draw_index].1;
+            self.triangle_rasterize(&triangle, &varyings, &mut **shader, framebuffer);
+        }
+    }
+
+    /// Draws every face of `model` with the binning rasterizer: triangles are
+    /// sorted into `TILE_SIZE` screen tiles, and the tiles are shaded in
+    /// parallel, each worker owning a clone of `shader`. Every tile sees its
+    /// triangles in submission order, so the result is identical to calling
+    /// `triangle_rasterize` for each face in turn.
+    ///
+    /// Every face is run through the vertex shader once, before binning, each
+    /// of its corners separately; see `draw_indexed` for sharing transformed
+    /// vertices between faces.
+    pub fn draw<S>(&mut self, model: &Model, shader: &S, framebuffer: &mut Framebuffer)
+    where
+        S: Shader + Clone + Send,
+        S::Varyings: Send + Sync,
+    {
+        let nfaces = model.get_nfaces();
+        self.draw_binned(
+            nfaces,
+            shader,
+            |shader, face_index| vertex_triangle(shader, model, face_index),
+            |_, _| {},
+            framebuffer,
+        );
+        self.stats.vertex_invocations += nfaces * 3;
+    }
+
+    /// Draws every face of `model` like `draw`, but runs the vertex shader only
+    /// once per distinct vertex of `index`, which has to be the one returned
+    /// by `model.get_vertex_index()`. Transformed vertices are kept for the
+    /// whole draw and shared by every face using them. The shader is run for
+    /// the first face corner of each vertex, so its outputs must only depend
+    /// on the attributes of the vertex, not on the face it is part of. Shaders
+    /// deriving anything from the other corners of the face, like a tangent
+    /// frame or a flat normal, have to be drawn with `draw` instead.
+    pub fn draw_indexed<S>(
+        &mut self,
+        model: &Model,
+        index: &VertexIndex,
+        shader: &S,
+        framebuffer: &mut Framebuffer,
+    ) where
+        S: Shader + Clone + Send,
+        S::Varyings: Send + Sync,
+    {
+        // post-transform cache, filled on first use of each vertex
+        let mut transformed: Vec<Option<(Vec4f32, S::Varyings)>> = vec![None; index.vertices.len()];
+        let mut vertex_shader = shader.clone();
+        for &vertex in index.indices.iter() {
+            if transformed[vertex].is_some() {
+                self.stats.vertex_cache_hits += 1;
+                continue;
+            }
+            let (face_index, nth_vertex) = index.vertices[vertex];
+            transformed[vertex] = Some(vertex_shader.vertex(model, face_index, nth_vertex));
+            self.stats.vertex_invocations += 1;
+        }
+
+        let transformed = &transformed;
+        self.draw_binned(
+            index.indices.len() / 3,
+            shader,
+            |_, face_index| {
+                let [a, b, c] = [0, 1, 2].map(|nth_vertex| {
+                    transformed[index.indices[face_index * 3 + nth_vertex]]
+                        .clone()
+                        .unwrap()
+                });
+                ([a.0, b.0, c.0], [a.1, b.1, c.1])
+            },
+            |_, _| {},
+            framebuffer,
+        );
+    }
+
+    /// Draws every face of `model` once for each of `instances`, instance by
+    /// instance, like `draw` would with the shader set to each instance in
+    /// turn through `Shader::set_instance`.
+    pub fn draw_instanced<S>(
+        &mut self,
+        model: &Model,
+        instances: &[Instance],
+        shader: &S,
+        framebuffer: &mut Framebuffer,
+    ) where
+        S: Shader + Clone + Send,
+        S::Varyings: Send + Sync,
+    {
+        let nfaces = model.get_nfaces();
+        if nfaces == 0 {
+            return;
+        }
+
+        let set_instance = |shader: &mut S, face_index: usize| {
+            let instance_id = face_index / nfaces;
+            shader.set_instance(instance_id, &instances[instance_id]);
+        };
+        self.draw_binned(
+            nfaces * instances.len(),
+            shader,
+            |shader, face_index| {
+                set_instance(shader, face_index);
+                vertex_triangle(shader, model, face_index % nfaces)
+            },
+            set_instance,
+            framebuffer,
+        );
+        self.stats.vertex_invocations += nfaces * instances.len() * 3;
+    }
+
+    // The binning rasterizer behind the draw calls. `face_triangle` runs the
+    // vertex stage of a face on a clone of `shader`, once per face before
+    // binning, and the tiles rasterize the stored result. `set_face` hands the
+    // shader of a worker any per-face state its fragment stage needs before
+    // the face is rasterized.
+    fn draw_binned<S, F, G>(
+        &mut self,
+        nfaces: usize,
+        shader: &S,
+        mut face_triangle: F,
+        set_face: G,
+        framebuffer: &mut Framebuffer,
+    ) where
+        S: Shader + Clone + Send,
+        S::Varyings: Send + Sync,
+        F: FnMut(&mut S, usize) -> ([Vec4f32; 3], [S::Varyings; 3]),
+        G: Fn(&mut S, usize) + Sync,
+    {
+        let (origin_x, origin_y) = framebuffer.get_origin();
+        let (width, height) = (framebuffer.get_width(), framebuffer.get_height());
+        if width == 0 || height == 0 {
+            return;
+        }
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+
+        let to_tile = |value: f32, origin: u32, tiles: u32| -> u32 {
+            let tile = ((value - origin as f32) / TILE_SIZE as f32).floor();
+            tile.clamp(0f32, (tiles - 1) as f32) as u32
+        };
+
+        // binning, keeping the transformed faces that reach any tile
+        let mut faces = vec![];
+        let mut bins: Vec<Vec<usize>> = vec![vec![]; (tiles_x * tiles_y) as usize];
+        let mut binning_shader = shader.clone();
+        for face_index in 0..nfaces {
+            let (triangle, varyings) = face_triangle(&mut binning_shader, face_index);
+            let assembly = self.assemble(&triangle);
+            self.record(&assembly);
+
+            let mut bounds = match screen_bounds(&triangle, &assembly, &self.viewport) {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            if self.polygon_mode == PolygonMode::Point {
+                let half_size = (self.point_size / 2f32).ceil();
+                bounds[0] -= half_size;
+                bounds[1] -= half_size;
+                bounds[2] += half_size;
+                bounds[3] += half_size;
+            }
+            if bounds[2] < origin_x as f32
+                || bounds[3] < origin_y as f32
+                || bounds[0] > (origin_x + width) as f32
+                || bounds[1] > (origin_y + height) as f32
+            {
+                continue;
+            }
+
+            for tile_y in
+                to_tile(bounds[1], origin_y, tiles_y)..=to_tile(bounds[3], origin_y, tiles_y)
+            {
+                for tile_x in
+                    to_tile(bounds[0], origin_x, tiles_x)..=to_tile(bounds[2], origin_x, tiles_x)
+                {
+                    bins[(tile_x + tile_y * tiles_x) as usize].push(faces.len());
+                }
+            }
+            faces.push((face_index, triangle, varyings, assembly));
+        }
+
+        let mut tiles = vec![];
+        for tile_y in 0..tiles_y {
+            for tile_x in 0..tiles_x {
+                let bin = std::mem::take(&mut bins[(tile_x + tile_y * tiles_x) as usize]);
+                if bin.is_empty() {
+                    continue;
+                }
+
+                let (x, y) = (tile_x * TILE_SIZE, tile_y * TILE_SIZE);
+                let tile = framebuffer
+                    .read_tile(x, y, TILE_SIZE.min(width - x), TILE_SIZE.min(height - y))
+                    .unwrap();
+                tiles.push((tile, bin));
+            }
+        }
+
+        let threads = if self.threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
         } else {
-            vertex[0].floor()
+            self.threads
         };
-        bbox_min[1] = if bbox_min[1] < vertex[1].floor() {
-            bbox_min[1]
+        let workers = threads.min(tiles.len());
+        let queue = Mutex::new(tiles.iter_mut());
+        let pipeline = &*self;
+        let (faces, set_face) = (&faces, &set_face);
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let queue = &queue;
+                let mut shader = shader.clone();
+
+                scope.spawn(move || loop {
+                    let next = queue.lock().unwrap().next();
+                    let (tile, bin) = match next {
+                        Some(work) => work,
+                        None => break,
+                    };
+
+                    for &face in bin.iter() {
+                        let (face_index, triangle, varyings, assembly) = &faces[face];
+                        set_face(&mut shader, *face_index);
+                        pipeline.rasterize_assembly(
+                            triangle,
+                            varyings,
+                            assembly,
+                            &mut shader,
+                            tile,
+                        );
+                    }
+                });
+            }
+        });
+
+        for (tile, _) in tiles.iter() {
+            framebuffer.write_tile(tile).unwrap();
+        }
+    }
+
+    #[inline(always)]
+    fn depth_passes(&self, framebuffer: &Framebuffer, depth: f32, stored: f32) -> bool {
+        if !self.depth_test {
+            return true;
+        }
+
+        if framebuffer.is_reversed_z() {
+            self.depth_compare.mirrored().test(depth, stored)
+        } else {
+            self.depth_compare.test(depth, stored)
+        }
+    }
+
+    // `depth` moved towards the camera by the depth bias
+    fn biased_depth(&self, framebuffer: &Framebuffer, depth: f32) -> f32 {
+        if framebuffer.is_reversed_z() {
+            depth + self.depth_bias
         } else {
-            vertex[1].floor()
+            depth - self.depth_bias
+        }
+    }
+
+    // Stencil and depth tests a line or point fragment at screen position
+    // (`x`, `y`) and writes it to every sample of the pixel that passes. `shade` runs only if
+    // some sample passed, and may discard the fragment by returning false.
+    fn write_fragment(
+        &self,
+        framebuffer: &mut Framebuffer,
+        x: i64,
+        y: i64,
+        depth: f32,
+        front_facing: bool,
+        shade: impl FnOnce(&mut TextureColor) -> bool,
+    ) {
+        let (origin_x, origin_y) = framebuffer.get_origin();
+        let (local_x, local_y) = (x - origin_x as i64, y - origin_y as i64);
+        if local_x < 0
+            || local_y < 0
+            || local_x >= framebuffer.get_width() as i64
+            || local_y >= framebuffer.get_height() as i64
+        {
+            return;
+        }
+        let (local_x, local_y) = (local_x as u32, local_y as u32);
+
+        let samples = framebuffer.get_samples() as usize;
+        let pixel_index = (local_x + local_y * framebuffer.get_width()) as usize * samples;
+        let sample_depth = [depth; MAX_SAMPLES];
+        let coverage = (1u32 << samples) - 1;
+        let passed = self.test_samples(
+            framebuffer,
+            pixel_index,
+            coverage,
+            &sample_depth,
+            front_facing,
+        );
+        if passed == 0 {
+            return;
+        }
+
+        let mut color = TextureColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
         };
-        bbox_max[0] = if bbox_max[0] > vertex[0].ceil() {
-            bbox_max[0]
+        if shade(&mut color) {
+            self.write_samples(
+                framebuffer,
+                (local_x, local_y),
+                passed,
+                &sample_depth,
+                color,
+                front_facing,
+            );
+        }
+    }
+
+    // Stencil and depth tests the samples in `coverage` of the pixel whose
+    // first sample is at `pixel_index`, applying the stencil fail and depth
+    // fail operations right away. Returns the samples passing both tests.
+    fn test_samples(
+        &self,
+        framebuffer: &mut Framebuffer,
+        pixel_index: usize,
+        coverage: u32,
+        sample_depth: &[f32; MAX_SAMPLES],
+        front_facing: bool,
+    ) -> u32 {
+        let samples = framebuffer.get_samples() as usize;
+        let stencil_test = self.stencil_test && framebuffer.has_stencil();
+        let face = self.stencil_state.get_face(front_facing);
+
+        let mut passed = 0u32;
+        for s in (0..samples).filter(|s| coverage & (1 << s) != 0) {
+            let index = pixel_index + s;
+
+            if stencil_test {
+                let stencil = framebuffer.get_stencil_attachment_mut().unwrap();
+                if !self.stencil_state.test(front_facing, stencil[index]) {
+                    stencil[index] = self.stencil_state.update(face.fail, stencil[index]);
+                    continue;
+                }
+            }
+
+            let stored = framebuffer.get_depth_attachment()[index];
+            if self.depth_passes(framebuffer, sample_depth[s], stored) {
+                passed |= 1 << s;
+            } else if stencil_test {
+                let stencil = framebuffer.get_stencil_attachment_mut().unwrap();
+                stencil[index] = self.stencil_state.update(face.depth_fail, stencil[index]);
+            }
+        }
+        passed
+    }
+
+    // Writes a shaded fragment to the samples of pixel `local` set in `mask`,
+    // blending it with what is already there.
+    fn write_samples(
+        &self,
+        framebuffer: &mut Framebuffer,
+        local: (u32, u32),
+        mask: u32,
+        sample_depth: &[f32; MAX_SAMPLES],
+        color: TextureColor,
+        front_facing: bool,
+    ) {
+        let samples = framebuffer.get_samples() as usize;
+        let pixel_index = (local.0 + local.1 * framebuffer.get_width()) as usize * samples;
+        let covered = (0..samples).filter(|s| mask & (1 << s) != 0);
+
+        if self.stencil_test {
+            let pass = self.stencil_state.get_face(front_facing).pass;
+            if let Some(stencil) = framebuffer.get_stencil_attachment_mut() {
+                for s in covered.clone() {
+                    let index = pixel_index + s;
+                    stencil[index] = self.stencil_state.update(pass, stencil[index]);
+                }
+            }
+        }
+
+        if self.order_independent {
+            if !self.color_write {
+                return;
+            }
+
+            // the closest of the covered samples
+            let reversed_z = framebuffer.is_reversed_z();
+            let depth = covered
+                .map(|s| sample_depth[s])
+                .reduce(|a, b| if (a < b) != reversed_z { a } else { b })
+                .unwrap();
+            framebuffer
+                .push_fragment(local.0, local.1, depth, color, mask)
+                .unwrap();
+            return;
+        }
+
+        if self.depth_test && self.depth_write {
+            for s in covered.clone() {
+                framebuffer.get_depth_attachment_mut()[pixel_index + s] = sample_depth[s];
+            }
+        }
+        if !self.color_write {
+            return;
+        }
+        match framebuffer.get_sample_color_attachment_mut() {
+            Some(sample_color) => {
+                for s in covered {
+                    let dst = sample_color[pixel_index + s];
+                    sample_color[pixel_index + s] = self.blend_state.blend(color, dst);
+                }
+            }
+            None => {
+                let texture = framebuffer.get_color_attachment_mut();
+                let dst = texture.get_color(local.0, local.1).unwrap();
+                texture
+                    .set_color(local.0, local.1, self.blend_state.blend(color, dst))
+                    .unwrap();
+            }
+        }
+    }
+
+    // DDA along the major axis: every pixel center column (or row) the segment
+    // passes is drawn once, at the row (or column) the segment crosses it.
+    // Segments are half-open, the pixel at their end point is left to the
+    // next segment of a strip or loop.
+    fn clipped_line_rasterize<S: Shader + ?Sized>(
+        &self,
+        line: &[clip::ClipVertex; 2],
+        varyings: &[S::Varyings; 3],
+        front_facing: bool,
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        let screen = [
+            &self.viewport * &line[0].position,
+            &self.viewport * &line[1].position,
+        ];
+        let a = [screen[0][0] / screen[0][3], screen[0][1] / screen[0][3]];
+        let b = [screen[1][0] / screen[1][3], screen[1][1] / screen[1][3]];
+        let depth = [screen[0][2] / screen[0][3], screen[1][2] / screen[1][3]];
+
+        let major = if (b[0] - a[0]).abs() >= (b[1] - a[1]).abs() {
+            0
         } else {
-            vertex[0].ceil()
+            1
         };
-        bbox_max[1] = if bbox_max[1] > vertex[1].ceil() {
-            bbox_max[1]
+        let minor = 1 - major;
+        let length = b[major] - a[major];
+        if length == 0f32 {
+            return;
+        }
+
+        let (origin_x, origin_y) = framebuffer.get_origin();
+        let first_pixel = [origin_x as i64, origin_y as i64];
+        let last_pixel = [
+            (origin_x + framebuffer.get_width()) as i64 - 1,
+            (origin_y + framebuffer.get_height()) as i64 - 1,
+        ];
+
+        // pixel centers `c + 0.5` with `(c + 0.5 - a) / length` in [0, 1)
+        let (first, last) = if length > 0f32 {
+            (
+                (a[major] - 0.5).ceil() as i64,
+                (b[major] - 0.5).ceil() as i64 - 1,
+            )
         } else {
-            vertex[1].ceil()
+            (
+                (b[major] - 0.5).floor() as i64 + 1,
+                (a[major] - 0.5).floor() as i64,
+            )
+        };
+        let first = first.max(first_pixel[major]);
+        let last = last.min(last_pixel[major]);
+
+        for c in first..=last {
+            let t = (c as f32 + 0.5 - a[major]) / length;
+            let m = (a[minor] + t * (b[minor] - a[minor])).floor() as i64;
+
+            // depth is linear in screen space, varyings are interpolated
+            // perspective correct
+            let depth = depth[0] + t * (depth[1] - depth[0]);
+            let t = t / screen[1][3] / ((1f32 - t) / screen[0][3] + t / screen[1][3]);
+            let barycentric =
+                &line[0].barycentric + &(&(&line[1].barycentric - &line[0].barycentric) * t);
+
+            let (x, y) = if major == 0 { (c, m) } else { (m, c) };
+            let depth = self.biased_depth(framebuffer, depth);
+            self.write_fragment(framebuffer, x, y, depth, front_facing, |color| {
+                shader.fragment(&S::Varyings::interpolate(varyings, &barycentric), color)
+            });
+        }
+    }
+
+    // Square sprite of `point_size` pixels, covering the pixels whose centers
+    // lie inside it.
+    fn clipped_point_rasterize<S: Shader + ?Sized>(
+        &self,
+        point: &Vec4f32,
+        varyings: &S::Varyings,
+        front_facing: bool,
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        if clip::outcode(point) != 0 || self.point_size <= 0f32 {
+            return;
+        }
+
+        let screen = &self.viewport * point;
+        let corner = [
+            screen[0] / screen[3] - self.point_size / 2f32,
+            screen[1] / screen[3] - self.point_size / 2f32,
+        ];
+
+        let (origin_x, origin_y) = framebuffer.get_origin();
+        let first_pixel = [origin_x as i64, origin_y as i64];
+        let last_pixel = [
+            (origin_x + framebuffer.get_width()) as i64 - 1,
+            (origin_y + framebuffer.get_height()) as i64 - 1,
+        ];
+        let range = |axis: usize| {
+            let first = (corner[axis] - 0.5).ceil() as i64;
+            let last = (corner[axis] + self.point_size - 0.5).ceil() as i64 - 1;
+            first.max(first_pixel[axis])..=last.min(last_pixel[axis])
         };
+
+        let depth = self.biased_depth(framebuffer, screen[2] / screen[3]);
+        for y in range(1) {
+            for x in range(0) {
+                let point_coord = Vec2f32::new_from_array([
+                    (x as f32 + 0.5 - corner[0]) / self.point_size,
+                    (y as f32 + 0.5 - corner[1]) / self.point_size,
+                ]);
+                self.write_fragment(framebuffer, x, y, depth, front_facing, |color| {
+                    shader.point_fragment(varyings, &point_coord, color)
+                });
+            }
+        }
     }
-    if bbox_min[0] < 0f32 {
-        bbox_min[0] = 0f32
+
+    // `barycentric_map` turns barycentric coordinates of `triangle` into those of
+    // the original triangle handed to the vertex shader, whose `varyings` are
+    // interpolated with them.
+    //
+    // Coverage is decided with edge functions evaluated at pixel centers on
+    // vertices snapped to `SUBPIXEL_BITS` of fixed-point precision. Integer edge
+    // functions step exactly from pixel to pixel, and together with the top-left
+    // fill rule every pixel center on an edge shared by two triangles is drawn
+    // by exactly one of them.
+    fn clipped_triangle_rasterize<S: Shader + ?Sized>(
+        &self,
+        triangle: &[Vec4f32; 3],
+        barycentric_map: &Matrix<f32, 3, 3>,
+        varyings: &[S::Varyings; 3],
+        front_facing: bool,
+        shader: &mut S,
+        framebuffer: &mut Framebuffer,
+    ) {
+        let screen_triangle: [Vec4f32; 3] = [
+            &self.viewport * &triangle[0],
+            &self.viewport * &triangle[1],
+            &self.viewport * &triangle[2],
+        ];
+        let fixed_triangle: [[i64; 2]; 3] = [
+            to_fixed_point(&screen_triangle[0]),
+            to_fixed_point(&screen_triangle[1]),
+            to_fixed_point(&screen_triangle[2]),
+        ];
+
+        // twice the signed area, positive for counter-clockwise triangles
+        let [v0, v1, v2] = fixed_triangle;
+        let area = (v1[0] - v0[0]) * (v2[1] - v0[1]) - (v2[0] - v0[0]) * (v1[1] - v0[1]);
+        if area == 0 {
+            return;
+        }
+
+        // walk clockwise triangles in reverse so that the edge functions are
+        // positive inside either way; `order` maps edge slots back to vertices
+        let order = if area > 0 { [0, 1, 2] } else { [0, 2, 1] };
+        let area = area.abs();
+        let edges = [
+            Edge::new(&fixed_triangle[order[1]], &fixed_triangle[order[2]]),
+            Edge::new(&fixed_triangle[order[2]], &fixed_triangle[order[0]]),
+            Edge::new(&fixed_triangle[order[0]], &fixed_triangle[order[1]]),
+        ];
+
+        let (origin_x, origin_y) = framebuffer.get_origin();
+        let (last_x, last_y) = (
+            (origin_x + framebuffer.get_width() - 1) as i64,
+            (origin_y + framebuffer.get_height() - 1) as i64,
+        );
+        let half_pixel = 1i64 << (SUBPIXEL_BITS - 1);
+        let fixed_min = |axis: usize| fixed_triangle.iter().map(|v| v[axis]).min().unwrap();
+        let fixed_max = |axis: usize| fixed_triangle.iter().map(|v| v[axis]).max().unwrap();
+        let bbox_min = [
+            (fixed_min(0) >> SUBPIXEL_BITS).max(origin_x as i64),
+            (fixed_min(1) >> SUBPIXEL_BITS).max(origin_y as i64),
+        ];
+        let bbox_max = [
+            (fixed_max(0) >> SUBPIXEL_BITS).min(last_x),
+            (fixed_max(1) >> SUBPIXEL_BITS).min(last_y),
+        ];
+        if bbox_min[0] > bbox_max[0] || bbox_min[1] > bbox_max[1] {
+            return;
+        }
+
+        // depth buffer values z / w, which are linear in screen space
+        let depth = Vec3f32::new_from_array([
+            screen_triangle[0][2] / screen_triangle[0][3],
+            screen_triangle[1][2] / screen_triangle[1][3],
+            screen_triangle[2][2] / screen_triangle[2][3],
+        ]);
+        let samples = framebuffer.get_samples() as usize;
+        let sample_offsets = sample_positions(framebuffer.get_samples());
+
+        // barycentric interpolation and perspective correct
+        let perspective_barycentric = |w: &[i64; 3]| -> Vec3f32 {
+            let mut clip_barycentric = Vec3f32::new();
+            for i in 0..3 {
+                clip_barycentric[order[i]] =
+                    w[i] as f32 / area as f32 / screen_triangle[order[i]][3];
+            }
+            let sum = clip_barycentric[0] + clip_barycentric[1] + clip_barycentric[2];
+            for i in 0..3 {
+                clip_barycentric[i] /= sum;
+            }
+            clip_barycentric
+        };
+
+        let start = [
+            (bbox_min[0] << SUBPIXEL_BITS) + half_pixel,
+            (bbox_min[1] << SUBPIXEL_BITS) + half_pixel,
+        ];
+        let mut row = [
+            edges[0].evaluate(&start),
+            edges[1].evaluate(&start),
+            edges[2].evaluate(&start),
+        ];
+
+        for y in bbox_min[1]..=bbox_max[1] {
+            let mut w = row;
+
+            for x in bbox_min[0]..=bbox_max[0] {
+                let mut sample_w = [[0i64; 3]; MAX_SAMPLES];
+                let mut coverage = 0u32;
+                for (s, offset) in sample_offsets.iter().enumerate() {
+                    for i in 0..3 {
+                        sample_w[s][i] = w[i] + edges[i].offset(offset);
+                    }
+                    if (0..3).all(|i| sample_w[s][i] + edges[i].bias >= 0) {
+                        coverage |= 1 << s;
+                    }
+                }
+
+                if coverage != 0 {
+                    let (local_x, local_y) = (x as u32 - origin_x, y as u32 - origin_y);
+                    let pixel_index =
+                        (local_x + local_y * framebuffer.get_width()) as usize * samples;
+
+                    // per-sample stencil and depth tests
+                    let mut sample_depth = [0f32; MAX_SAMPLES];
+                    for s in (0..samples).filter(|s| coverage & (1 << s) != 0) {
+                        let sample = (0..3)
+                            .map(|i| sample_w[s][i] as f32 / area as f32 * depth[order[i]])
+                            .sum();
+                        sample_depth[s] = self.biased_depth(framebuffer, sample);
+                    }
+                    let passed = self.test_samples(
+                        framebuffer,
+                        pixel_index,
+                        coverage,
+                        &sample_depth,
+                        front_facing,
+                    );
+
+                    if passed != 0 {
+                        // shade once per pixel, at its center unless the center lies
+                        // outside the triangle, then at the first covered sample
+                        let center_covered = (0..3).all(|i| w[i] + edges[i].bias >= 0);
+                        let shading_w = if center_covered {
+                            &w
+                        } else {
+                            &sample_w[coverage.trailing_zeros() as usize]
+                        };
+                        let clip_barycentric = perspective_barycentric(shading_w);
+
+                        let mut color: TextureColor = TextureColor {
+                            r: 0,
+                            g: 0,
+                            b: 0,
+                            a: 255,
+                        };
+                        let varyings = S::Varyings::interpolate(
+                            varyings,
+                            &(barycentric_map * &clip_barycentric),
+                        );
+                        if shader.fragment(&varyings, &mut color) {
+                            self.write_samples(
+                                framebuffer,
+                                (local_x, local_y),
+                                passed,
+                                &sample_depth,
+                                color,
+                                front_facing,
+                            );
+                        }
+                    }
+                }
+
+                for i in 0..3 {
+                    w[i] += edges[i].step_x;
+                }
+            }
+
+            for i in 0..3 {
+                row[i] += edges[i].step_y;
+            }
+        }
     }
-    if bbox_min[1] < 0f32 {
-        bbox_min[1] = 0f32
+}
+
+enum Assembly {
+    Culled,
+    Outside,
+    Inside,
+    Clipped(Vec<clip::ClipVertex>),
+}
+
+// Integer edge function of the directed edge `a -> b`, positive on its left.
+struct Edge {
+    a: [i64; 2],
+    dx: i64,
+    dy: i64,
+    // increments for moving one whole pixel along x and y
+    step_x: i64,
+    step_y: i64,
+    // -1 on edges that are neither top nor left, so that pixel centers lying
+    // exactly on them are left to the neighbouring triangle
+    bias: i64,
+}
+
+impl Edge {
+    fn new(a: &[i64; 2], b: &[i64; 2]) -> Self {
+        let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+        // with y pointing up and the interior on the left, top edges run
+        // towards -x and left edges run downwards
+        let top_left = (dy == 0 && dx < 0) || dy < 0;
+
+        Edge {
+            a: *a,
+            dx,
+            dy,
+            step_x: -dy << SUBPIXEL_BITS,
+            step_y: dx << SUBPIXEL_BITS,
+            bias: if top_left { 0 } else { -1 },
+        }
     }
-    if bbox_max[0] > (frame.get_width() - 1) as f32 {
-        bbox_max[0] = (frame.get_width() - 1) as f32
+
+    #[inline(always)]
+    fn evaluate(&self, point: &[i64; 2]) -> i64 {
+        self.dx * (point[1] - self.a[1]) - self.dy * (point[0] - self.a[0])
     }
-    if bbox_max[1] > (frame.get_height() - 1) as f32 {
-        bbox_max[1] = (frame.get_height() - 1) as f32
+
+    // change of the edge function when moving by `offset`
+    #[inline(always)]
+    fn offset(&self, offset: &[i64; 2]) -> i64 {
+        self.dx * offset[1] - self.dy * offset[0]
     }
+}
+
+const MAX_SAMPLES: usize = 8;
 
-    let bbox_min: [u32; 2] = unsafe {
-        [
-            bbox_min[0].to_int_unchecked(),
-            bbox_min[1].to_int_unchecked(),
-        ]
+// Sample positions relative to the pixel center in 1/16 pixel, following the
+// standard D3D patterns.
+const SAMPLE_POSITIONS_1: [[i64; 2]; 1] = [[0, 0]];
+const SAMPLE_POSITIONS_2: [[i64; 2]; 2] = [[4, 4], [-4, -4]];
+const SAMPLE_POSITIONS_4: [[i64; 2]; 4] = [[-2, -6], [6, -2], [-6, 2], [2, 6]];
+const SAMPLE_POSITIONS_8: [[i64; 2]; 8] = [
+    [1, -3],
+    [-1, 3],
+    [5, 1],
+    [-3, -5],
+    [-5, 5],
+    [-7, -1],
+    [3, 7],
+    [7, -7],
+];
+
+// sample positions for `samples` samples per pixel in fixed-point units
+fn sample_positions(samples: u32) -> Vec<[i64; 2]> {
+    let positions: &[[i64; 2]] = match samples {
+        2 => &SAMPLE_POSITIONS_2,
+        4 => &SAMPLE_POSITIONS_4,
+        8 => &SAMPLE_POSITIONS_8,
+        _ => &SAMPLE_POSITIONS_1,
     };
-    let bbox_max: [u32; 2] = unsafe {
-        [
-            bbox_max[0].to_int_unchecked(),
-            bbox_max[1].to_int_unchecked(),
-        ]
+    positions
+        .iter()
+        .map(|p| [p[0] << (SUBPIXEL_BITS - 4), p[1] << (SUBPIXEL_BITS - 4)])
+        .collect()
+}
+
+#[inline(always)]
+fn to_fixed_point(screen: &Vec4f32) -> [i64; 2] {
+    let scale = (1u32 << SUBPIXEL_BITS) as f32;
+    [
+        (screen[0] / screen[3] * scale).round() as i64,
+        (screen[1] / screen[3] * scale).round() as i64,
+    ]
+}
+
+fn barycentric_basis(nth_vertex: usize) -> Vec3f32 {
+    let mut barycentric = Vec3f32::new();
+    barycentric[nth_vertex] = 1f32;
+    barycentric
+}
+
+fn vertex_triangle<S: Shader + ?Sized>(
+    shader: &mut S,
+    model: &Model,
+    face_index: usize,
+) -> ([Vec4f32; 3], [S::Varyings; 3]) {
+    let [(a, a_varyings), (b, b_varyings), (c, c_varyings)] =
+        [0, 1, 2].map(|nth_vertex| shader.vertex(model, face_index, nth_vertex));
+    ([a, b, c], [a_varyings, b_varyings, c_varyings])
+}
+
+// Screen-space bounding box `[min_x, min_y, max_x, max_y]` of whatever is left
+// of the triangle after clipping.
+fn screen_bounds(
+    triangle: &[Vec4f32; 3],
+    assembly: &Assembly,
+    viewport: &Matrix<f32, 4, 4>,
+) -> Option<[f32; 4]> {
+    let vertices: Vec<Vec4f32> = match assembly {
+        Assembly::Culled | Assembly::Outside => return None,
+        Assembly::Inside => triangle.to_vec(),
+        Assembly::Clipped(polygon) => polygon.iter().map(|vertex| vertex.position).collect(),
     };
 
-    for x in bbox_min[0]..=bbox_max[0] {
-        for y in bbox_min[1]..=bbox_max[1] {
-            // barycentric interpolation and perspective correct
-            let screen_barycentric = barycentric_coordinates(
-                &screen_triangle_perspective,
-                &Vec2f32::new_from_vec(&vec![x as f32, y as f32]),
-            );
-            let mut clip_barycentric = Vec3f32::new_from_vec(&vec![
-                screen_barycentric[0] / screen_triangle[0][3],
-                screen_barycentric[1] / screen_triangle[1][3],
-                screen_barycentric[2] / screen_triangle[2][3],
-            ]);
-            clip_barycentric = &clip_barycentric
-                / (clip_barycentric[0] + clip_barycentric[1] + clip_barycentric[2]);
-
-            // interpolate depth
-            let fragment_depth =
-                &Vec3f32::new_from_vec(&vec![triangle[0][2], triangle[1][2], triangle[2][2]])
-                    * &clip_barycentric;
-            let fragment_index = (x + y * frame.get_width()) as usize;
-
-            if screen_barycentric[0] < 0f32
-                || screen_barycentric[1] < 0f32
-                || screen_barycentric[2] < 0f32
-                || fragment_depth > zbuffer[fragment_index]
-            {
-                continue;
-            }
+    let mut bounds = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    for vertex in vertices.iter() {
+        let screen = viewport * vertex;
+        let (x, y) = (screen[0] / screen[3], screen[1] / screen[3]);
+        bounds = [
+            bounds[0].min(x.floor()),
+            bounds[1].min(y.floor()),
+            bounds[2].max(x.ceil()),
+            bounds[3].max(y.ceil()),
+        ];
+    }
+    Some(bounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::tests::get_colors;
+    use stencil::{StencilFace, StencilOp};
+
+    const WIDTH: u32 = 150;
+    const HEIGHT: u32 = 130;
+
+    #[derive(Clone)]
+    struct TestShader;
+
+    impl Shader for TestShader {
+        type Varyings = Vec3f32;
 
-            let mut color: TextureColor = TextureColor {
-                r: 0,
-                g: 0,
-                b: 0,
+        fn vertex(
+            &mut self,
+            model: &Model,
+            face_index: usize,
+            nth_vertex: usize,
+        ) -> (Vec4f32, Self::Varyings) {
+            let position = model.get_vertex(face_index, nth_vertex);
+            let varyings = [position[0], position[1], position[2]];
+            (position, Vec3f32::new_from_array(varyings))
+        }
+
+        fn fragment(&mut self, varyings: &Self::Varyings, color: &mut TextureColor) -> bool {
+            *color = TextureColor {
+                r: (varyings[0] * 127f32 + 128f32) as u8,
+                g: (varyings[1] * 127f32 + 128f32) as u8,
+                b: (varyings[2] * 255f32) as u8,
                 a: 255,
             };
-            if shader.fragment(&clip_barycentric, &mut color) {
-                zbuffer[fragment_index] = fragment_depth;
-                frame.set_color(x, y, color).unwrap();
+            true
+        }
+    }
+
+    // Grid of jittered triangles covering the whole viewport, with edges
+    // shared between neighbours, alternating windings and diagonals, and a
+    // row and a column of vertices on pixel centers, so that some edges run
+    // exactly through pixel centers.
+    fn shared_edge_mesh() -> Model {
+        let (columns, rows) = (13, 11);
+        let mut seed = 1u32;
+        let mut jitter = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((seed >> 16) % 1000) as f32 / 1000f32 - 0.5
+        };
+        let to_pixel_center = |value: f32, size: u32| {
+            let pixel = ((value + 1f32) * size as f32 / 2f32).floor() + 0.5;
+            pixel * 2f32 / size as f32 - 1f32
+        };
+
+        let mut model = Model::new();
+        for j in 0..=rows {
+            for i in 0..=columns {
+                let mut x = -1f32 + 2f32 * i as f32 / columns as f32;
+                let mut y = -1f32 + 2f32 * j as f32 / rows as f32;
+                if i > 0 && i < columns && j > 0 && j < rows {
+                    x += jitter() * 1.2 / columns as f32;
+                    y += jitter() * 1.2 / rows as f32;
+                }
+                if i == 5 {
+                    x = to_pixel_center(x, WIDTH);
+                }
+                if j == 4 {
+                    y = to_pixel_center(y, HEIGHT);
+                }
+                let z = 0.25 + 0.1 * x * y;
+                model
+                    .vertices
+                    .push(Vec4f32::new_from_array([x, y, z, 1f32]));
+            }
+        }
+
+        let vertex = |i: usize, j: usize| i + j * (columns + 1);
+        for j in 0..rows {
+            for i in 0..columns {
+                let (a, b) = (vertex(i, j), vertex(i + 1, j));
+                let (c, d) = (vertex(i + 1, j + 1), vertex(i, j + 1));
+                let faces = if (i + j) % 2 == 0 {
+                    [[a, b, c], [a, c, d]]
+                } else {
+                    [[a, b, d], [b, c, d]]
+                };
+                for (n, mut face) in faces.into_iter().enumerate() {
+                    if (i + n) % 3 == 0 {
+                        face.swap(1, 2);
+                    }
+                    model.face_vertex_indices.extend(face);
+                }
+            }
+        }
+        model
+    }
+
+    // Every sample counts the fragments written to it in its stencil value.
+    fn counting_pipeline() -> Pipeline {
+        let counting = StencilFace {
+            pass: StencilOp::IncrementWrap,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new();
+        pipeline.set_viewport(viewport(0, 0, WIDTH, HEIGHT));
+        pipeline.set_depth_compare(CompareFunc::Always);
+        pipeline.set_stencil_test(true);
+        pipeline.set_stencil_state(StencilState {
+            front: counting,
+            back: counting,
+            ..Default::default()
+        });
+        pipeline.set_threads(4);
+        pipeline
+    }
+
+    // every attachment of `framebuffer` as bytes
+    fn get_bytes(framebuffer: &Framebuffer) -> Vec<u8> {
+        let colors = get_colors(framebuffer.get_color_attachment());
+        let sample_colors = framebuffer
+            .get_sample_color_attachment()
+            .unwrap_or_default();
+        let mut bytes: Vec<u8> = colors.into_iter().flatten().collect();
+        for color in sample_colors {
+            bytes.extend([color.r, color.g, color.b, color.a]);
+        }
+        for depth in framebuffer.get_depth_attachment() {
+            bytes.extend(depth.to_le_bytes());
+        }
+        bytes.extend(framebuffer.get_stencil_attachment().unwrap());
+        bytes
+    }
+
+    // square covering the whole viewport at clip-space `w` of `distance`,
+    // colored blue by its `z`
+    fn square(distance: f32, z: f32) -> Model {
+        let mut model = Model::new();
+        for (x, y) in [(-1f32, -1f32), (1f32, -1f32), (1f32, 1f32), (-1f32, 1f32)] {
+            let position = [x * distance, y * distance, z, distance];
+            model.vertices.push(Vec4f32::new_from_array(position));
+        }
+        model.face_vertex_indices.extend([0, 1, 2, 0, 2, 3]);
+        model
+    }
+
+    #[test]
+    fn sorted_draws_run_the_vertex_shader_once() {
+        let (near, far) = (square(1f32, 0.1), square(2f32, 0.2));
+        let mut pipeline = Pipeline::new();
+        pipeline.set_viewport(viewport(0, 0, 4, 4));
+        pipeline.set_depth_test(false);
+        let mut framebuffer = Framebuffer::new(4, 4);
+        let (mut near_shader, mut far_shader) = (TestShader, TestShader);
+        pipeline.draw_sorted(
+            &mut [(&near, &mut near_shader), (&far, &mut far_shader)],
+            &mut framebuffer,
+        );
+
+        // the near square, submitted first, is drawn last
+        let colors = get_colors(framebuffer.get_color_attachment());
+        assert!(colors.iter().all(|color| color[2] == (0.1 * 255f32) as u8));
+        assert_eq!(pipeline.get_stats().vertex_invocations, 12);
+    }
+
+    #[test]
+    fn shared_edges_are_drawn_once() {
+        let model = shared_edge_mesh();
+        let index = model.get_vertex_index();
+        for samples in [1, 4] {
+            let new_framebuffer = || {
+                let mut framebuffer = Framebuffer::new_with_stencil(WIDTH, HEIGHT);
+                framebuffer.set_samples(samples).unwrap();
+                framebuffer
+            };
+
+            let mut pipeline = counting_pipeline();
+            let mut serial = new_framebuffer();
+            let mut shader = TestShader;
+            for face_index in 0..model.get_nfaces() {
+                let (triangle, varyings) = vertex_triangle(&mut shader, &model, face_index);
+                pipeline.triangle_rasterize(&triangle, &varyings, &mut shader, &mut serial);
             }
+            // the mesh covers every sample, each exactly once
+            let stencil = serial.get_stencil_attachment().unwrap();
+            assert!(stencil.iter().all(|&count| count == 1));
+            let serial = get_bytes(&serial);
+
+            let mut binned = new_framebuffer();
+            pipeline.draw(&model, &TestShader, &mut binned);
+            assert!(get_bytes(&binned) == serial);
+
+            let mut indexed = new_framebuffer();
+            pipeline.draw_indexed(&model, &index, &TestShader, &mut indexed);
+            assert!(get_bytes(&indexed) == serial);
         }
     }
 }
+
+pub mod blend;
+pub mod clip;
+pub mod shadow_volume;
+pub mod stencil;
@@ -0,0 +1,158 @@
+use crate::texture::TextureColor;
+
+/// Weight applied to the source (fragment) or destination (framebuffer)
+/// color before the two are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+}
+
+/// How the weighted source and destination are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendEquation {
+    /// `src * src_factor + dst * dst_factor`
+    Add,
+    /// `src * src_factor - dst * dst_factor`
+    Subtract,
+    /// `dst * dst_factor - src * src_factor`
+    ReverseSubtract,
+}
+
+/// Blending of fragment colors into the framebuffer, with separate factors
+/// and equations for the color and alpha channels. Results are clamped to
+/// [0, 1] per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlendState {
+    pub src_color: BlendFactor,
+    pub dst_color: BlendFactor,
+    pub color_equation: BlendEquation,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor,
+    pub alpha_equation: BlendEquation,
+}
+
+impl Default for BlendState {
+    fn default() -> Self {
+        BlendState::REPLACE
+    }
+}
+
+impl BlendState {
+    /// Fragments overwrite the framebuffer, which is what the pipeline does
+    /// unless told otherwise.
+    pub const REPLACE: BlendState = BlendState {
+        src_color: BlendFactor::One,
+        dst_color: BlendFactor::Zero,
+        color_equation: BlendEquation::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::Zero,
+        alpha_equation: BlendEquation::Add,
+    };
+
+    /// Classic "over" compositing of straight (non premultiplied) alpha.
+    pub const ALPHA: BlendState = BlendState {
+        src_color: BlendFactor::SrcAlpha,
+        dst_color: BlendFactor::OneMinusSrcAlpha,
+        color_equation: BlendEquation::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::OneMinusSrcAlpha,
+        alpha_equation: BlendEquation::Add,
+    };
+
+    /// "Over" compositing of fragments whose color is already multiplied by
+    /// their alpha.
+    pub const PREMULTIPLIED_ALPHA: BlendState = BlendState {
+        src_color: BlendFactor::One,
+        dst_color: BlendFactor::OneMinusSrcAlpha,
+        color_equation: BlendEquation::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::OneMinusSrcAlpha,
+        alpha_equation: BlendEquation::Add,
+    };
+
+    /// Adds the fragment, weighted by its alpha, on top of the framebuffer.
+    pub const ADDITIVE: BlendState = BlendState {
+        src_color: BlendFactor::SrcAlpha,
+        dst_color: BlendFactor::One,
+        color_equation: BlendEquation::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::One,
+        alpha_equation: BlendEquation::Add,
+    };
+
+    pub fn blend(&self, src: TextureColor, dst: TextureColor) -> TextureColor {
+        if *self == BlendState::REPLACE {
+            return src;
+        }
+
+        let src = to_float(src);
+        let dst = to_float(dst);
+
+        let mut result = [0f32; 4];
+        for (channel, value) in result.iter_mut().enumerate() {
+            let (src_factor, dst_factor, equation) = if channel < 3 {
+                (self.src_color, self.dst_color, self.color_equation)
+            } else {
+                (self.src_alpha, self.dst_alpha, self.alpha_equation)
+            };
+
+            let s = src[channel] * src_factor.weight(&src, &dst, channel);
+            let d = dst[channel] * dst_factor.weight(&src, &dst, channel);
+            *value = match equation {
+                BlendEquation::Add => s + d,
+                BlendEquation::Subtract => s - d,
+                BlendEquation::ReverseSubtract => d - s,
+            };
+        }
+
+        to_color(&result)
+    }
+}
+
+impl BlendFactor {
+    #[inline(always)]
+    fn weight(self, src: &[f32; 4], dst: &[f32; 4], channel: usize) -> f32 {
+        match self {
+            BlendFactor::Zero => 0f32,
+            BlendFactor::One => 1f32,
+            BlendFactor::SrcColor => src[channel],
+            BlendFactor::OneMinusSrcColor => 1f32 - src[channel],
+            BlendFactor::DstColor => dst[channel],
+            BlendFactor::OneMinusDstColor => 1f32 - dst[channel],
+            BlendFactor::SrcAlpha => src[3],
+            BlendFactor::OneMinusSrcAlpha => 1f32 - src[3],
+            BlendFactor::DstAlpha => dst[3],
+            BlendFactor::OneMinusDstAlpha => 1f32 - dst[3],
+        }
+    }
+}
+
+#[inline(always)]
+fn to_float(color: TextureColor) -> [f32; 4] {
+    [
+        color.r as f32 / 255f32,
+        color.g as f32 / 255f32,
+        color.b as f32 / 255f32,
+        color.a as f32 / 255f32,
+    ]
+}
+
+#[inline(always)]
+fn to_color(color: &[f32; 4]) -> TextureColor {
+    let channel = |value: f32| (value.clamp(0f32, 1f32) * 255f32).round() as u8;
+    TextureColor {
+        r: channel(color[0]),
+        g: channel(color[1]),
+        b: channel(color[2]),
+        a: channel(color[3]),
+    }
+}
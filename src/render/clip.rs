@@ -0,0 +1,167 @@
+use crate::math::vector::{Vec3f32, Vec4f32};
+
+/// Smallest homogeneous `w` a vertex may have after clipping. Everything
+/// closer to (or behind) the center of projection is cut away, so the
+/// perspective divide never sees a zero or negative `w`.
+pub const W_NEAR: f32 = 1e-3;
+
+/// Side planes are placed at `±GUARD_BAND * w` instead of `±w`. Geometry
+/// slightly outside the viewport but still on screen keeps being drawn, while
+/// triangles reaching far off screen are still cut down to a sane size.
+pub const GUARD_BAND: f32 = 4f32;
+
+const PLANE_NEAR: u8 = 1 << 0;
+const PLANE_LEFT: u8 = 1 << 1;
+const PLANE_RIGHT: u8 = 1 << 2;
+const PLANE_BOTTOM: u8 = 1 << 3;
+const PLANE_TOP: u8 = 1 << 4;
+
+const PLANES: [u8; 5] = [PLANE_NEAR, PLANE_LEFT, PLANE_RIGHT, PLANE_BOTTOM, PLANE_TOP];
+
+/// A vertex of a clipped polygon. `barycentric` holds its weights relative to
+/// the original (unclipped) triangle, which is what shaders interpolate their
+/// varyings with.
+#[derive(Debug, Clone, Copy)]
+pub struct ClipVertex {
+    pub position: Vec4f32,
+    pub barycentric: Vec3f32,
+}
+
+pub enum ClipResult {
+    Inside,
+    Outside,
+    Clipped(Vec<ClipVertex>),
+}
+
+#[inline(always)]
+fn plane_distance(plane: u8, vertex: &Vec4f32) -> f32 {
+    match plane {
+        PLANE_NEAR => vertex[3] - W_NEAR,
+        PLANE_LEFT => vertex[0] + GUARD_BAND * vertex[3],
+        PLANE_RIGHT => GUARD_BAND * vertex[3] - vertex[0],
+        PLANE_BOTTOM => vertex[1] + GUARD_BAND * vertex[3],
+        PLANE_TOP => GUARD_BAND * vertex[3] - vertex[1],
+        _ => unreachable!(),
+    }
+}
+
+#[inline(always)]
+pub fn outcode(vertex: &Vec4f32) -> u8 {
+    let mut code = 0u8;
+    for plane in PLANES {
+        if plane_distance(plane, vertex) < 0f32 {
+            code |= plane;
+        }
+    }
+    code
+}
+
+#[inline(always)]
+fn interpolate(a: &ClipVertex, b: &ClipVertex, t: f32) -> ClipVertex {
+    ClipVertex {
+        position: &a.position + &(&(&b.position - &a.position) * t),
+        barycentric: &a.barycentric + &(&(&b.barycentric - &a.barycentric) * t),
+    }
+}
+
+#[inline(always)]
+fn intersect(a: &ClipVertex, b: &ClipVertex, distance_a: f32, distance_b: f32) -> ClipVertex {
+    interpolate(a, b, distance_a / (distance_a - distance_b))
+}
+
+/// One Sutherland–Hodgman pass of `polygon` against a single plane.
+fn clip_polygon(polygon: &[ClipVertex], plane: u8) -> Vec<ClipVertex> {
+    let mut output = Vec::with_capacity(polygon.len() + 1);
+
+    for i in 0..polygon.len() {
+        let current = &polygon[i];
+        let next = &polygon[(i + 1) % polygon.len()];
+        let distance_current = plane_distance(plane, &current.position);
+        let distance_next = plane_distance(plane, &next.position);
+
+        if distance_current >= 0f32 {
+            output.push(*current);
+        }
+        if (distance_current >= 0f32) != (distance_next >= 0f32) {
+            output.push(intersect(current, next, distance_current, distance_next));
+        }
+    }
+
+    output
+}
+
+/// Clips a clip-space triangle against the near plane and the guard band side
+/// planes. Triangles entirely inside or entirely outside one plane are reported
+/// without allocating; the others are returned as a convex polygon that can be
+/// triangulated as a fan.
+pub fn clip_triangle(triangle: &[Vec4f32; 3]) -> ClipResult {
+    let codes = [
+        outcode(&triangle[0]),
+        outcode(&triangle[1]),
+        outcode(&triangle[2]),
+    ];
+
+    if codes[0] | codes[1] | codes[2] == 0 {
+        return ClipResult::Inside;
+    }
+    if codes[0] & codes[1] & codes[2] != 0 {
+        return ClipResult::Outside;
+    }
+
+    let mut polygon = vec![
+        ClipVertex {
+            position: triangle[0],
+            barycentric: Vec3f32::new_from_array([1f32, 0f32, 0f32]),
+        },
+        ClipVertex {
+            position: triangle[1],
+            barycentric: Vec3f32::new_from_array([0f32, 1f32, 0f32]),
+        },
+        ClipVertex {
+            position: triangle[2],
+            barycentric: Vec3f32::new_from_array([0f32, 0f32, 1f32]),
+        },
+    ];
+
+    let crossed = codes[0] | codes[1] | codes[2];
+    for plane in PLANES {
+        if crossed & plane == 0 {
+            continue;
+        }
+
+        polygon = clip_polygon(&polygon, plane);
+        if polygon.len() < 3 {
+            return ClipResult::Outside;
+        }
+    }
+
+    ClipResult::Clipped(polygon)
+}
+
+/// Clips a segment against the same planes as `clip_triangle`, returning the
+/// part of it that is left, if any.
+pub fn clip_line(line: &[ClipVertex; 2]) -> Option<[ClipVertex; 2]> {
+    let (mut t0, mut t1) = (0f32, 1f32);
+
+    for plane in PLANES {
+        let distance_a = plane_distance(plane, &line[0].position);
+        let distance_b = plane_distance(plane, &line[1].position);
+
+        if distance_a < 0f32 && distance_b < 0f32 {
+            return None;
+        }
+        if distance_a < 0f32 {
+            t0 = t0.max(distance_a / (distance_a - distance_b));
+        } else if distance_b < 0f32 {
+            t1 = t1.min(distance_a / (distance_a - distance_b));
+        }
+    }
+
+    if t0 > t1 {
+        return None;
+    }
+    Some([
+        interpolate(&line[0], &line[1], t0),
+        interpolate(&line[0], &line[1], t1),
+    ])
+}
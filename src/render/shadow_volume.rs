@@ -0,0 +1,206 @@
+use crate::{
+    frame::Framebuffer,
+    math::{matrix::Matrix, vector::Vec4f32},
+    model::Model,
+    texture::TextureColor,
+};
+
+use super::{
+    stencil::{StencilFace, StencilOp, StencilState},
+    CompareFunc, CullMode, Pipeline, PolygonMode, Shader,
+};
+
+/// Depth buffer units the volume is pushed away from the camera by when it is
+/// counted, which is plenty to cover rounding differences between the
+/// transforms of the volume and of the model.
+pub const SHADOW_VOLUME_DEPTH_BIAS: f32 = 1e-5;
+
+/// Closed volume of the space a model shadows from a light, for stencil
+/// shadows. It is made of the faces turned away from the light (the near
+/// cap), the same faces moved to infinity (the far cap) and quads extruded
+/// from the silhouette edges in between, all in model space. The volume is
+/// itself a model holding only vertex positions, points at infinity having
+/// `w` = 0.
+///
+/// Models are expected to be wound counter-clockwise seen from outside, as
+/// usual for OBJ files. Capping with the faces turned away from the light
+/// keeps the volume clear of the lit surfaces, which therefore never shadow
+/// themselves.
+#[derive(Debug, Clone)]
+pub struct ShadowVolume {
+    model: Model,
+}
+
+impl ShadowVolume {
+    /// Builds the volume of `model` for a `light` given in model space, as a
+    /// position (`w` = 1) or as the direction towards a light infinitely far
+    /// away (`w` = 0). `adjacency` is the one returned by
+    /// `Model::get_adjacency`.
+    pub fn new(model: &Model, adjacency: &[[Option<usize>; 3]], light: &Vec4f32) -> Self {
+        let nfaces = model.get_nfaces();
+
+        // a face is lit if its normal points to the light's side; faces
+        // without area count as lit so that they add nothing to the volume
+        let lit: Vec<bool> = (0..nfaces)
+            .map(|face_index| {
+                let [a, b, c] = [0, 1, 2].map(|nth| model.get_vertex(face_index, nth));
+                let normal = (&b - &a).cross(&(&c - &a));
+                let to_light = &light.project::<3>().embed(0f32) - &(&a * light[3]);
+                &normal * &normal == 0f32 || &normal * &to_light > 0f32
+            })
+            .collect();
+
+        // the point at infinity away from the light behind `vertex`
+        let extrude = |vertex: &Vec4f32| -> Vec4f32 {
+            let mut direction = &(vertex * light[3]) - light;
+            direction[3] = 0f32;
+            direction
+        };
+
+        let mut triangles = vec![];
+        for face_index in (0..nfaces).filter(|&face_index| !lit[face_index]) {
+            let [a, b, c] = [0, 1, 2].map(|nth| model.get_vertex(face_index, nth));
+
+            // the near cap faces the light, the far cap faces away from it
+            triangles.push([a, c, b]);
+            triangles.push([extrude(&a), extrude(&b), extrude(&c)]);
+
+            for (nth, neighbour) in adjacency[face_index].iter().enumerate() {
+                let on_silhouette = match *neighbour {
+                    Some(neighbour) => lit[neighbour],
+                    None => true,
+                };
+                if !on_silhouette {
+                    continue;
+                }
+
+                let a = model.get_vertex(face_index, nth);
+                let b = model.get_vertex(face_index, (nth + 1) % 3);
+                let (a_far, b_far) = (extrude(&a), extrude(&b));
+                triangles.push([a, b, b_far]);
+                triangles.push([a, b_far, a_far]);
+            }
+        }
+
+        ShadowVolume {
+            model: Model {
+                face_vertex_indices: (0..triangles.len() * 3).collect(),
+                vertices: triangles.concat(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn get_model(&self) -> &Model {
+        &self.model
+    }
+}
+
+/// Transforms a shadow volume like the model it was built from. Fragments
+/// carry no color, the volume only touches the stencil attachment.
+#[derive(Debug, Clone)]
+pub struct ShadowVolumeShader {
+    model_view_projection: Matrix<f32, 4, 4>,
+}
+
+impl ShadowVolumeShader {
+    pub fn new(model_view: Matrix<f32, 4, 4>, projection: Matrix<f32, 4, 4>) -> Self {
+        ShadowVolumeShader {
+            model_view_projection: &projection * &model_view,
+        }
+    }
+}
+
+impl Shader for ShadowVolumeShader {
+    type Varyings = ();
+
+    fn vertex(&mut self, model: &Model, face_index: usize, nth_vertex: usize) -> (Vec4f32, ()) {
+        let vertex = model.get_vertex(face_index, nth_vertex);
+        (&self.model_view_projection * &vertex, ())
+    }
+
+    fn fragment(&mut self, _varyings: &(), _color: &mut TextureColor) -> bool {
+        true
+    }
+}
+
+/// Counts the shadow volume into the stencil attachment with the depth-fail
+/// method: samples behind a back face of the volume are incremented, samples
+/// behind a front face decremented. Afterwards samples inside the volume have
+/// a non-zero stencil value, wherever the camera is.
+///
+/// The depth attachment has to hold the finished scene and the stencil
+/// attachment must have been cleared to zero before the first volume. Only
+/// the viewport and threading of `pipeline` are used; it is left unchanged.
+pub fn draw_shadow_volume(
+    pipeline: &Pipeline,
+    volume: &ShadowVolume,
+    shader: &ShadowVolumeShader,
+    framebuffer: &mut Framebuffer,
+) {
+    let counting = |depth_fail| StencilFace {
+        compare: CompareFunc::Always,
+        fail: StencilOp::Keep,
+        depth_fail,
+        pass: StencilOp::Keep,
+    };
+
+    let mut pipeline = pipeline.clone();
+    pipeline.set_cull_mode(CullMode::None);
+    pipeline.set_polygon_mode(PolygonMode::Fill);
+    pipeline.set_order_independent(false);
+    pipeline.set_color_write(false);
+    pipeline.set_depth_test(true);
+    pipeline.set_depth_compare(CompareFunc::Less);
+    // the near cap lies on the surfaces it was built from, pushing the volume
+    // back makes them fail the depth test consistently, so that they end up
+    // inside the volume
+    pipeline.set_depth_bias(-SHADOW_VOLUME_DEPTH_BIAS);
+    pipeline.set_depth_write(false);
+    pipeline.set_stencil_test(true);
+    pipeline.set_stencil_state(StencilState {
+        front: counting(StencilOp::DecrementWrap),
+        back: counting(StencilOp::IncrementWrap),
+        ..Default::default()
+    });
+
+    pipeline.draw(volume.get_model(), shader, framebuffer);
+}
+
+/// Scales the color of every sample with a non-zero stencil value by
+/// `intensity`, leaving alpha alone. Run after `draw_shadow_volume` and
+/// before `Framebuffer::resolve`.
+pub fn darken_shadowed(framebuffer: &mut Framebuffer, intensity: f32) {
+    let darken = |color: &mut TextureColor| {
+        color.r = (color.r as f32 * intensity) as u8;
+        color.g = (color.g as f32 * intensity) as u8;
+        color.b = (color.b as f32 * intensity) as u8;
+    };
+
+    let stencil = match framebuffer.get_stencil_attachment() {
+        Some(stencil) => stencil.to_vec(),
+        None => return,
+    };
+
+    match framebuffer.get_sample_color_attachment_mut() {
+        Some(sample_color) => {
+            for (color, _) in sample_color
+                .iter_mut()
+                .zip(stencil.iter())
+                .filter(|(_, &value)| value != 0)
+            {
+                darken(color);
+            }
+        }
+        None => {
+            let width = framebuffer.get_width();
+            let texture = framebuffer.get_color_attachment_mut();
+            for (index, _) in stencil.iter().enumerate().filter(|(_, &value)| value != 0) {
+                let (x, y) = (index as u32 % width, index as u32 / width);
+                let mut color = texture.get_color(x, y).unwrap();
+                darken(&mut color);
+                texture.set_color(x, y, color).unwrap();
+            }
+        }
+    }
+}
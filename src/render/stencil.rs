@@ -0,0 +1,117 @@
+use super::CompareFunc;
+
+/// Update applied to a stencil value, see `StencilFace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StencilOp {
+    #[default]
+    Keep,
+    Zero,
+    /// Sets the value to the reference.
+    Replace,
+    /// Increments, saturating at 255.
+    IncrementClamp,
+    /// Decrements, saturating at 0.
+    DecrementClamp,
+    Invert,
+    /// Increments, wrapping 255 around to 0.
+    IncrementWrap,
+    /// Decrements, wrapping 0 around to 255.
+    DecrementWrap,
+}
+
+impl StencilOp {
+    #[inline(always)]
+    pub fn apply(self, value: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::Keep => value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::IncrementClamp => value.saturating_add(1),
+            StencilOp::DecrementClamp => value.saturating_sub(1),
+            StencilOp::Invert => !value,
+            StencilOp::IncrementWrap => value.wrapping_add(1),
+            StencilOp::DecrementWrap => value.wrapping_sub(1),
+        }
+    }
+}
+
+/// Stencil test and updates for one facing of triangles. A sample passes
+/// when `reference & read_mask <compare> stored & read_mask` holds; `fail`
+/// is applied to samples failing it, `depth_fail` to samples passing it but
+/// failing the depth test, and `pass` to samples passing both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilFace {
+    pub compare: CompareFunc,
+    pub fail: StencilOp,
+    pub depth_fail: StencilOp,
+    pub pass: StencilOp,
+}
+
+impl Default for StencilFace {
+    fn default() -> Self {
+        StencilFace {
+            compare: CompareFunc::Always,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+        }
+    }
+}
+
+/// Stencil configuration of a pipeline. Front and back facing triangles have
+/// separate tests and operations, lines and points use `front`. Only the
+/// bits set in `write_mask` are ever changed by an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilState {
+    pub front: StencilFace,
+    pub back: StencilFace,
+    pub reference: u8,
+    pub read_mask: u8,
+    pub write_mask: u8,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        StencilState {
+            front: Default::default(),
+            back: Default::default(),
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+        }
+    }
+}
+
+impl StencilState {
+    /// The same test and operations for both facings.
+    pub fn new(face: StencilFace, reference: u8) -> Self {
+        StencilState {
+            front: face,
+            back: face,
+            reference,
+            ..Default::default()
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_face(&self, front_facing: bool) -> &StencilFace {
+        if front_facing {
+            &self.front
+        } else {
+            &self.back
+        }
+    }
+
+    #[inline(always)]
+    pub fn test(&self, front_facing: bool, stored: u8) -> bool {
+        self.get_face(front_facing)
+            .compare
+            .test(self.reference & self.read_mask, stored & self.read_mask)
+    }
+
+    /// `stored` after applying `op`, restricted to the bits of `write_mask`.
+    #[inline(always)]
+    pub fn update(&self, op: StencilOp, stored: u8) -> u8 {
+        (stored & !self.write_mask) | (op.apply(stored, self.reference) & self.write_mask)
+    }
+}
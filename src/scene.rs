@@ -0,0 +1,322 @@
+use crate::{
+    frame::Framebuffer,
+    math::{matrix::Matrix, vector::Vec4f32},
+    model::{Model, VertexIndex},
+    render::{self, CullMode, Pipeline, Shader},
+    texture::Texture,
+    utils,
+};
+
+/// A model together with the vertex index used to draw it indexed.
+pub struct Mesh {
+    pub model: Model,
+    pub vertex_index: VertexIndex,
+}
+
+impl Mesh {
+    pub fn new(model: Model) -> Self {
+        let vertex_index = model.get_vertex_index();
+        Mesh {
+            model,
+            vertex_index,
+        }
+    }
+}
+
+/// Surface description handed to the shaders drawing a mesh.
+#[derive(Default)]
+pub struct Material {
+    pub diffuse_map: Option<Texture>,
+    pub normal_map: Option<Texture>,
+    pub specular_map: Option<Texture>,
+    pub cull_mode: CullMode,
+}
+
+/// Projection of a camera node, which looks along its local +z axis with +y
+/// up, like the views built by `render::lookat`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Camera {
+    Pinhole {
+        focal_length: f32,
+    },
+    Perspective {
+        fov_y: f32,
+        aspect: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Camera {
+    pub fn get_projection(&self) -> Matrix<f32, 4, 4> {
+        match *self {
+            Camera::Pinhole { focal_length } => render::projection_pinhole(focal_length),
+            Camera::Perspective {
+                fov_y,
+                aspect,
+                near,
+                far,
+            } => render::projection_perspective(fov_y, aspect, near, far),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    /// Infinitely far away, shining along the node's local +z axis.
+    Directional { intensity: f32 },
+    /// Shining in every direction from the node's origin.
+    Point { intensity: f32 },
+}
+
+impl Light {
+    pub fn get_intensity(&self) -> f32 {
+        match *self {
+            Light::Directional { intensity } | Light::Point { intensity } => intensity,
+        }
+    }
+}
+
+/// A mesh drawn with a material, both given as indices into the scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshRef {
+    pub mesh: usize,
+    pub material: usize,
+}
+
+/// Element of the scene graph. Its transform maps its local space to that of
+/// its parent; a node may carry a mesh, a camera and a light at once.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub transform: Matrix<f32, 4, 4>,
+    pub mesh: Option<MeshRef>,
+    pub camera: Option<Camera>,
+    pub light: Option<Light>,
+    /// Whether the mesh of the node occludes lights, for the shadow passes.
+    pub cast_shadows: bool,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.into(),
+            transform: render::identity(),
+            mesh: None,
+            camera: None,
+            light: None,
+            cast_shadows: true,
+            parent: None,
+            children: vec![],
+        }
+    }
+
+    /// A node placed at `eye` and looking at `center`, for cameras and lights.
+    pub fn new_looking_at(name: &str, eye: Vec4f32, center: Vec4f32, up: Vec4f32) -> Self {
+        Node {
+            transform: render::lookat(eye, center, up).inv(),
+            ..Node::new(name)
+        }
+    }
+
+    pub fn get_parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    pub fn get_children(&self) -> &[usize] {
+        &self.children
+    }
+}
+
+/// A light of the scene, placed in world space as a point (`w` = 1) or as the
+/// direction towards the light (`w` = 0).
+#[derive(Debug, Clone, Copy)]
+pub struct SceneLight {
+    pub light: Light,
+    pub position: Vec4f32,
+}
+
+/// Everything needed to draw one mesh node as seen from a camera.
+pub struct Draw<'a> {
+    pub node: usize,
+    pub mesh: &'a Mesh,
+    pub material: &'a Material,
+    /// Local to world transform of the node.
+    pub world: Matrix<f32, 4, 4>,
+    pub view: Matrix<f32, 4, 4>,
+    pub model_view: Matrix<f32, 4, 4>,
+    pub projection: Matrix<f32, 4, 4>,
+    /// All lights of the scene.
+    pub lights: Vec<SceneLight>,
+}
+
+/// Scene graph of nodes with hierarchical transforms, owning the meshes and
+/// materials the nodes refer to. Nodes are referred to by their index, and
+/// are always added after their parent.
+#[derive(Default)]
+pub struct Scene {
+    nodes: Vec<Node>,
+    meshes: Vec<Mesh>,
+    materials: Vec<Material>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> usize {
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    pub fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    /// Adds `node` as a child of `parent`, or as a root, returning its index.
+    pub fn add_node(&mut self, parent: Option<usize>, mut node: Node) -> utils::Result<usize> {
+        if let Some(mesh) = node.mesh {
+            if mesh.mesh >= self.meshes.len() || mesh.material >= self.materials.len() {
+                return Err("node refers to a mesh or material that does not exist".into());
+            }
+        }
+        let index = self.nodes.len();
+        if let Some(parent) = parent {
+            self.nodes
+                .get_mut(parent)
+                .ok_or("parent node does not exist")?
+                .children
+                .push(index);
+        }
+
+        node.parent = parent;
+        node.children.clear();
+        self.nodes.push(node);
+        Ok(index)
+    }
+
+    pub fn get_nnodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn get_node_mut(&mut self, index: usize) -> &mut Node {
+        &mut self.nodes[index]
+    }
+
+    /// The first node with the given name.
+    pub fn find_node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    pub fn get_mesh(&self, index: usize) -> &Mesh {
+        &self.meshes[index]
+    }
+
+    pub fn get_material(&self, index: usize) -> &Material {
+        &self.materials[index]
+    }
+
+    /// Local to world transforms of all nodes.
+    pub fn get_world_transforms(&self) -> Vec<Matrix<f32, 4, 4>> {
+        // parents come first, so one pass in order is enough
+        let mut world: Vec<Matrix<f32, 4, 4>> = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let transform = match node.parent {
+                Some(parent) => &world[parent] * &node.transform,
+                None => node.transform,
+            };
+            world.push(transform);
+        }
+        world
+    }
+
+    pub fn get_lights(&self) -> Vec<SceneLight> {
+        let world = self.get_world_transforms();
+        self.nodes
+            .iter()
+            .zip(world.iter())
+            .filter_map(|(node, world)| {
+                let light = node.light?;
+                let position = match light {
+                    Light::Directional { .. } => {
+                        let mut towards_light = world.get_col(2);
+                        towards_light[3] = 0f32;
+                        &towards_light * -1f32
+                    }
+                    Light::Point { .. } => world.get_col(3),
+                };
+                Some(SceneLight { light, position })
+            })
+            .collect()
+    }
+
+    /// The draws of every mesh node as seen from node `camera`, in node
+    /// order.
+    pub fn get_draws(&self, camera: usize) -> utils::Result<Vec<Draw<'_>>> {
+        let projection = self
+            .nodes
+            .get(camera)
+            .and_then(|node| node.camera)
+            .ok_or("node is not a camera")?
+            .get_projection();
+        let world = self.get_world_transforms();
+        let view = world[camera].inv();
+        let lights = self.get_lights();
+
+        let draws = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| {
+                let mesh = node.mesh?;
+                Some((index, mesh))
+            })
+            .map(|(index, mesh)| Draw {
+                node: index,
+                mesh: &self.meshes[mesh.mesh],
+                material: &self.materials[mesh.material],
+                world: world[index],
+                view,
+                model_view: &view * &world[index],
+                projection,
+                lights: lights.clone(),
+            })
+            .collect();
+        Ok(draws)
+    }
+
+    /// Draws every mesh node as seen from node `camera`, with the shader
+    /// `shader` builds for each draw and the cull mode of its material. The
+    /// rest of the pipeline state, including the viewport, is used as is.
+    /// Meshes go through `Pipeline::draw`, so shaders may read the whole face
+    /// in their vertex stage.
+    pub fn render<'a, S, F>(
+        &'a self,
+        camera: usize,
+        pipeline: &mut Pipeline,
+        framebuffer: &mut Framebuffer,
+        mut shader: F,
+    ) -> utils::Result<()>
+    where
+        S: Shader + Clone + Send,
+        S::Varyings: Send + Sync,
+        F: FnMut(&Draw<'a>) -> S,
+    {
+        for draw in self.get_draws(camera)? {
+            pipeline.set_cull_mode(draw.material.cull_mode);
+            pipeline.draw(&draw.mesh.model, &shader(&draw), framebuffer);
+        }
+        Ok(())
+    }
+}
+
+pub mod animation;
+pub mod file;
@@ -0,0 +1,115 @@
+use crate::{
+    math::{matrix::Matrix, vector::Vec3f32},
+    render,
+};
+
+/// Parameters of `render::lookat` at a point in time of a camera path.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraKey {
+    pub time: f32,
+    pub eye: Vec3f32,
+    pub center: Vec3f32,
+    pub up: Vec3f32,
+}
+
+#[derive(Debug, Clone)]
+pub enum CameraPath {
+    /// Orbits the camera once around `axis` through `center`.
+    Turntable { center: Vec3f32, axis: Vec3f32 },
+    /// Follows smooth curves through the keys, which are sorted by time.
+    Keyframes(Vec<CameraKey>),
+}
+
+impl CameraPath {
+    /// Transform of a camera node at time `t`, running from 0 at the start of
+    /// the path to 1 at its end, for a camera with transform `start` when
+    /// not animated.
+    pub fn get_transform(&self, start: &Matrix<f32, 4, 4>, t: f32) -> Matrix<f32, 4, 4> {
+        match self {
+            CameraPath::Turntable { center, axis } => {
+                let orbit = &(&render::translation(*center)
+                    * &render::rotation(*axis, 2f32 * std::f32::consts::PI * t))
+                    * &render::translation(center * -1f32);
+                &orbit * start
+            }
+            CameraPath::Keyframes(keys) => {
+                let (first, last) = (keys[0].time, keys[keys.len() - 1].time);
+                let time = first + (last - first) * t;
+                let eye = interpolate(keys, time, |key| key.eye);
+                let center = interpolate(keys, time, |key| key.center);
+                let up = interpolate(keys, time, |key| key.up);
+                render::lookat(eye.embed(1f32), center.embed(1f32), up.embed(0f32)).inv()
+            }
+        }
+    }
+
+    /// Whether the end of the path is its start again.
+    pub fn is_looping(&self) -> bool {
+        matches!(self, CameraPath::Turntable { .. })
+    }
+}
+
+/// A camera path rendered as a sequence of frames.
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub frames: usize,
+    pub path: CameraPath,
+}
+
+impl Animation {
+    /// Time along the path of frame `frame`. Looping paths leave out their
+    /// end, which would repeat the first frame.
+    pub fn get_time(&self, frame: usize) -> f32 {
+        if self.path.is_looping() {
+            frame as f32 / self.frames as f32
+        } else if self.frames > 1 {
+            frame as f32 / (self.frames - 1) as f32
+        } else {
+            0f32
+        }
+    }
+
+    pub fn get_camera_transform(
+        &self,
+        start: &Matrix<f32, 4, 4>,
+        frame: usize,
+    ) -> Matrix<f32, 4, 4> {
+        self.path.get_transform(start, self.get_time(frame))
+    }
+}
+
+// Cubic Hermite interpolation of a key value, with the tangents of
+// Catmull-Rom splines adapted to keys unevenly spaced in time. The curve
+// stops at the first and last keys.
+fn interpolate<F: Fn(&CameraKey) -> Vec3f32>(keys: &[CameraKey], time: f32, value: F) -> Vec3f32 {
+    let n = keys.len();
+    if n == 1 || time <= keys[0].time {
+        return value(&keys[0]);
+    }
+    if time >= keys[n - 1].time {
+        return value(&keys[n - 1]);
+    }
+
+    let tangent = |i: usize| -> Vec3f32 {
+        let (previous, next) = (i.saturating_sub(1), (i + 1).min(n - 1));
+        let dt = keys[next].time - keys[previous].time;
+        if dt <= 0f32 {
+            return Vec3f32::new();
+        }
+        &(&value(&keys[next]) - &value(&keys[previous])) / dt
+    };
+
+    let i = keys.partition_point(|key| key.time <= time) - 1;
+    let h = keys[i + 1].time - keys[i].time;
+    if h <= 0f32 {
+        return value(&keys[i + 1]);
+    }
+    let s = (time - keys[i].time) / h;
+    let (s2, s3) = (s * s, s * s * s);
+
+    let p0 = &value(&keys[i]) * (2f32 * s3 - 3f32 * s2 + 1f32);
+    let m0 = &tangent(i) * ((s3 - 2f32 * s2 + s) * h);
+    let p1 = &value(&keys[i + 1]) * (-2f32 * s3 + 3f32 * s2);
+    let m1 = &tangent(i + 1) * ((s3 - s2) * h);
+    &(&(&p0 + &m0) + &p1) + &m1
+}
@@ -0,0 +1,727 @@
+//! Text description of a scene and how to render it.
+//!
+//! A scene file is a list of lines of whitespace separated words, with
+//! everything after a `#` being a comment. The first word of a line is a
+//! keyword. `settings`, `animation`, `mesh`, `material` and `node` start a
+//! new block, and the lines up to the next one set properties of that block;
+//! indenting them is only a convention. Relative input paths are relative to
+//! the directory of the scene file, the output path to the working directory,
+//! and angles are in degrees.
+//!
+//! ```text
+//! settings
+//!     resolution <width> <height>          # of the output image
+//!     shadow_resolution <width> <height>   # of the shadow map
+//!     camera <node>                        # defaults to the first camera
+//!     output <path>
+//!     shadows map | volumes | none
+//!     ambient_occlusion on | off
+//!     antialiasing on | off                # 4 samples per pixel
+//!     shading textured | smooth | flat | normals
+//!
+//! animation                                # of the camera
+//!     frames <count>
+//!     turntable <center x y z> <axis x y z>
+//!     key <time> <eye x y z> <center x y z> <up x y z>
+//!
+//! mesh <name> <obj path>
+//!
+//! material <name>
+//!     diffuse <image path>
+//!     normal <image path>                  # tangent-space normal map
+//!     specular <image path>
+//!     cull none | back | front
+//!
+//! node <name>
+//!     parent <node>                        # defined earlier in the file
+//!     draw <mesh> <material>
+//!     look_at <eye x y z> <center x y z> <up x y z>
+//!     translate <x> <y> <z>
+//!     rotate <axis x y z> <angle>
+//!     scale <x> <y> <z> | scale <factor>
+//!     camera pinhole <focal length>
+//!     camera perspective <fov y> <aspect> <near> <far>
+//!     light directional <intensity>        # shining along the node's +z
+//!     light point <intensity>
+//!     cast_shadows on | off
+//! ```
+//!
+//! The transform of a node maps it into the space of its parent. It starts
+//! as the identity, `look_at` replaces it with the transform placing a
+//! camera at `eye` looking at `center`, and `translate`, `rotate` and `scale`
+//! are applied on top of what the previous lines give, in the parent space.
+//! The light casting the shadow map is the first light node that also has a
+//! camera, which gives the projection of the map.
+//!
+//! An animation either orbits the camera once around the turntable axis, or
+//! moves it along smooth curves through two or more `look_at` keys, given in
+//! any order of time.
+//!
+//! Material images are read by their extension: `.tga`, `.png`, `.bmp`, or
+//! `.pgm`, `.ppm`, `.pnm` and `.pam` for Netpbm.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    math::{
+        matrix::Matrix,
+        vector::{Vec3f32, Vector},
+    },
+    model::Model,
+    render::{self, CullMode},
+    texture::{self, Texture},
+    utils,
+};
+
+use super::{
+    animation::{Animation, CameraKey, CameraPath},
+    Camera, Light, Material, Mesh, MeshRef, Node, Scene,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Shadows {
+    None,
+    #[default]
+    Map,
+    Volumes,
+}
+
+impl FromStr for Shadows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "map" => Ok(Shadows::Map),
+            "volumes" => Ok(Shadows::Volumes),
+            "none" => Ok(Shadows::None),
+            _ => Err(format!("unknown shadows {}", s)),
+        }
+    }
+}
+
+/// How surfaces are shaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Shading {
+    /// Textures with normal mapping.
+    #[default]
+    Textured,
+    /// Textures with the interpolated vertex normals.
+    Smooth,
+    /// Textures with the normal of each face.
+    Flat,
+    /// The shading normals as colors.
+    Normals,
+}
+
+impl FromStr for Shading {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "textured" => Ok(Shading::Textured),
+            "smooth" => Ok(Shading::Smooth),
+            "flat" => Ok(Shading::Flat),
+            "normals" => Ok(Shading::Normals),
+            _ => Err(format!("unknown shading {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub shadow_width: u32,
+    pub shadow_height: u32,
+    /// Name of the camera node, the first camera of the scene if `None`.
+    pub camera: Option<String>,
+    pub output: PathBuf,
+    pub shadows: Shadows,
+    pub ambient_occlusion: bool,
+    pub antialiasing: bool,
+    pub shading: Shading,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 800,
+            height: 800,
+            shadow_width: 1600,
+            shadow_height: 1600,
+            camera: None,
+            output: PathBuf::from("result.tga"),
+            shadows: Shadows::Map,
+            ambient_occlusion: true,
+            antialiasing: true,
+            shading: Shading::Textured,
+        }
+    }
+}
+
+pub struct SceneFile {
+    pub scene: Scene,
+    pub settings: RenderSettings,
+    pub animation: Option<Animation>,
+}
+
+impl SceneFile {
+    /// The node of the camera named in the settings, or the first camera.
+    pub fn get_camera(&self) -> utils::Result<usize> {
+        let camera = match &self.settings.camera {
+            Some(name) => self
+                .scene
+                .find_node(name)
+                .ok_or(format!("no node named {}", name))?,
+            None => (0..self.scene.get_nnodes())
+                .find(|&index| self.scene.get_node(index).camera.is_some())
+                .ok_or("scene has no camera")?,
+        };
+        if self.scene.get_node(camera).camera.is_none() {
+            return Err("node is not a camera".into());
+        }
+        Ok(camera)
+    }
+
+    /// The first light node with a camera, which renders the shadow map.
+    pub fn get_shadow_light(&self) -> Option<usize> {
+        (0..self.scene.get_nnodes()).find(|&index| {
+            let node = self.scene.get_node(index);
+            node.light.is_some() && node.camera.is_some()
+        })
+    }
+}
+
+pub fn read_from_file(path: &Path) -> utils::Result<SceneFile> {
+    let text = fs::read_to_string(path)?;
+    parse(&text, path.parent().unwrap_or(Path::new("")))
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Parses the scene file `text`, with relative paths resolved against `base`.
+pub fn parse(text: &str, base: &Path) -> utils::Result<SceneFile> {
+    let mut parser = Parser {
+        base,
+        scene: Scene::new(),
+        settings: Default::default(),
+        meshes: HashMap::new(),
+        materials: HashMap::new(),
+        animation: None,
+        block: Block::None,
+        block_line: 0,
+    };
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        if let Some((keyword, args)) = words.split_first() {
+            if BLOCK_KEYWORDS.contains(keyword) {
+                parser
+                    .finish_block()
+                    .map_err(|e| format!("line {}: {}", parser.block_line, e))?;
+                parser.block_line = index + 1;
+            }
+            parser
+                .parse_line(keyword, args)
+                .map_err(|e| format!("line {}: {}", index + 1, e))?;
+        }
+    }
+    parser
+        .finish_block()
+        .map_err(|e| format!("line {}: {}", parser.block_line, e))?;
+
+    Ok(SceneFile {
+        scene: parser.scene,
+        settings: parser.settings,
+        animation: parser.animation,
+    })
+}
+
+const BLOCK_KEYWORDS: [&str; 5] = ["settings", "animation", "mesh", "material", "node"];
+
+enum Block {
+    None,
+    Settings,
+    Animation(Option<usize>, Option<CameraPath>),
+    Material(String, Material),
+    Node(Node, Option<usize>),
+}
+
+struct Parser<'a> {
+    base: &'a Path,
+    scene: Scene,
+    settings: RenderSettings,
+    meshes: HashMap<String, usize>,
+    materials: HashMap<String, usize>,
+    animation: Option<Animation>,
+    block: Block,
+    /// Line starting `block`, which errors found when finishing it refer to.
+    block_line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_line(&mut self, keyword: &str, args: &[&str]) -> utils::Result<()> {
+        match keyword {
+            "settings" => {
+                expect_args(args, 0)?;
+                self.block = Block::Settings;
+            }
+            "animation" => {
+                expect_args(args, 0)?;
+                if self.animation.is_some() {
+                    return Err("duplicate animation".into());
+                }
+                self.block = Block::Animation(None, None);
+            }
+            "mesh" => {
+                expect_args(args, 2)?;
+                if self.meshes.contains_key(args[0]) {
+                    return Err(format!("duplicate mesh {}", args[0]).into());
+                }
+                let path = self.base.join(args[1]);
+                let model = Model::new_from_file(&path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                let mesh = self.scene.add_mesh(Mesh::new(model));
+                self.meshes.insert(args[0].into(), mesh);
+            }
+            "material" => {
+                expect_args(args, 1)?;
+                if self.materials.contains_key(args[0]) {
+                    return Err(format!("duplicate material {}", args[0]).into());
+                }
+                self.block = Block::Material(args[0].into(), Default::default());
+            }
+            "node" => {
+                expect_args(args, 1)?;
+                if self.scene.find_node(args[0]).is_some() {
+                    return Err(format!("duplicate node {}", args[0]).into());
+                }
+                self.block = Block::Node(Node::new(args[0]), None);
+            }
+            _ => match &mut self.block {
+                Block::None => return Err(format!("{} outside of a block", keyword).into()),
+                Block::Settings => parse_setting(&mut self.settings, keyword, args)?,
+                Block::Animation(frames, path) => parse_animation(frames, path, keyword, args)?,
+                Block::Material(_, material) => parse_material(material, self.base, keyword, args)?,
+                Block::Node(node, parent) => match keyword {
+                    "parent" => {
+                        expect_args(args, 1)?;
+                        *parent = Some(
+                            self.scene
+                                .find_node(args[0])
+                                .ok_or(format!("no node named {}", args[0]))?,
+                        );
+                    }
+                    "draw" => {
+                        expect_args(args, 2)?;
+                        node.mesh = Some(MeshRef {
+                            mesh: *self
+                                .meshes
+                                .get(args[0])
+                                .ok_or(format!("no mesh named {}", args[0]))?,
+                            material: *self
+                                .materials
+                                .get(args[1])
+                                .ok_or(format!("no material named {}", args[1]))?,
+                        });
+                    }
+                    _ => parse_node(node, keyword, args)?,
+                },
+            },
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> utils::Result<()> {
+        match std::mem::replace(&mut self.block, Block::None) {
+            Block::None | Block::Settings => {}
+            Block::Animation(frames, path) => {
+                let frames = frames.ok_or("animation has no frame count")?;
+                let path = match path {
+                    Some(CameraPath::Keyframes(mut keys)) => {
+                        if keys.len() < 2 {
+                            return Err("animation needs at least two keys".into());
+                        }
+                        keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+                        CameraPath::Keyframes(keys)
+                    }
+                    Some(path) => path,
+                    None => return Err("animation has no turntable or keys".into()),
+                };
+                self.animation = Some(Animation { frames, path });
+            }
+            Block::Material(name, material) => {
+                let material = self.scene.add_material(material);
+                self.materials.insert(name, material);
+            }
+            Block::Node(node, parent) => {
+                self.scene.add_node(parent, node)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_setting(settings: &mut RenderSettings, keyword: &str, args: &[&str]) -> utils::Result<()> {
+    match keyword {
+        "resolution" => [settings.width, settings.height] = parse_resolution(args)?,
+        "shadow_resolution" => {
+            [settings.shadow_width, settings.shadow_height] = parse_resolution(args)?
+        }
+        "camera" => {
+            expect_args(args, 1)?;
+            settings.camera = Some(args[0].into());
+        }
+        "output" => {
+            expect_args(args, 1)?;
+            settings.output = PathBuf::from(args[0]);
+        }
+        "shadows" => {
+            expect_args(args, 1)?;
+            settings.shadows = args[0].parse()?;
+        }
+        "ambient_occlusion" => settings.ambient_occlusion = parse_switch(args)?,
+        "antialiasing" => settings.antialiasing = parse_switch(args)?,
+        "shading" => {
+            expect_args(args, 1)?;
+            settings.shading = args[0].parse()?;
+        }
+        _ => return Err(format!("unknown setting {}", keyword).into()),
+    }
+    Ok(())
+}
+
+fn parse_animation(
+    frames: &mut Option<usize>,
+    path: &mut Option<CameraPath>,
+    keyword: &str,
+    args: &[&str],
+) -> utils::Result<()> {
+    match keyword {
+        "frames" => {
+            expect_args(args, 1)?;
+            *frames = match args[0].parse() {
+                Ok(frames) if frames > 0 => Some(frames),
+                _ => return Err(format!("{} is not a valid frame count", args[0]).into()),
+            };
+        }
+        "turntable" => {
+            if path.is_some() {
+                return Err("animation already has a camera path".into());
+            }
+            let floats = parse_floats::<6>(args)?;
+            *path = Some(CameraPath::Turntable {
+                center: Vec3f32::new_from_vec(&floats[0..3]),
+                axis: Vec3f32::new_from_vec(&floats[3..6]),
+            });
+        }
+        "key" => {
+            let floats = parse_floats::<10>(args)?;
+            let key = CameraKey {
+                time: floats[0],
+                eye: Vec3f32::new_from_vec(&floats[1..4]),
+                center: Vec3f32::new_from_vec(&floats[4..7]),
+                up: Vec3f32::new_from_vec(&floats[7..10]),
+            };
+            match path.get_or_insert(CameraPath::Keyframes(vec![])) {
+                CameraPath::Keyframes(keys) => keys.push(key),
+                _ => return Err("animation already has a camera path".into()),
+            }
+        }
+        _ => return Err(format!("unknown animation property {}", keyword).into()),
+    }
+    Ok(())
+}
+
+fn parse_material(
+    material: &mut Material,
+    base: &Path,
+    keyword: &str,
+    args: &[&str],
+) -> utils::Result<()> {
+    let load = |args: &[&str]| -> utils::Result<Option<Texture>> {
+        expect_args(args, 1)?;
+        let path = base.join(args[0]);
+        let texture =
+            texture::read_from_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some(texture))
+    };
+    match keyword {
+        "diffuse" => material.diffuse_map = load(args)?,
+        "normal" => material.normal_map = load(args)?,
+        "specular" => material.specular_map = load(args)?,
+        "cull" => {
+            expect_args(args, 1)?;
+            material.cull_mode = match args[0] {
+                "none" => CullMode::None,
+                "back" => CullMode::Back,
+                "front" => CullMode::Front,
+                _ => return Err(format!("unknown cull mode {}", args[0]).into()),
+            };
+        }
+        _ => return Err(format!("unknown material property {}", keyword).into()),
+    }
+    Ok(())
+}
+
+fn parse_node(node: &mut Node, keyword: &str, args: &[&str]) -> utils::Result<()> {
+    let transform: Option<Matrix<f32, 4, 4>> = match keyword {
+        "look_at" => {
+            let floats = parse_floats::<9>(args)?;
+            node.transform = render::lookat(
+                Vec3f32::new_from_vec(&floats[0..3]).embed(1f32),
+                Vec3f32::new_from_vec(&floats[3..6]).embed(1f32),
+                Vec3f32::new_from_vec(&floats[6..9]).embed(0f32),
+            )
+            .inv();
+            None
+        }
+        "translate" => Some(render::translation(Vector::new_from_array(parse_floats(
+            args,
+        )?))),
+        "rotate" => {
+            let [x, y, z, angle] = parse_floats(args)?;
+            Some(render::rotation(
+                Vector::new_from_array([x, y, z]),
+                angle.to_radians(),
+            ))
+        }
+        "scale" => {
+            let factors = if args.len() == 1 {
+                [parse_floats::<1>(args)?[0]; 3]
+            } else {
+                parse_floats(args)?
+            };
+            Some(render::scaling(Vector::new_from_array(factors)))
+        }
+        "camera" => {
+            let (kind, args) = args.split_first().ok_or("missing camera type")?;
+            node.camera = Some(match *kind {
+                "pinhole" => {
+                    let [focal_length] = parse_floats(args)?;
+                    Camera::Pinhole { focal_length }
+                }
+                "perspective" => {
+                    let [fov_y, aspect, near, far] = parse_floats(args)?;
+                    Camera::Perspective {
+                        fov_y: fov_y.to_radians(),
+                        aspect,
+                        near,
+                        far,
+                    }
+                }
+                _ => return Err(format!("unknown camera type {}", kind).into()),
+            });
+            None
+        }
+        "light" => {
+            let (kind, args) = args.split_first().ok_or("missing light type")?;
+            let [intensity] = parse_floats(args)?;
+            node.light = Some(match *kind {
+                "directional" => Light::Directional { intensity },
+                "point" => Light::Point { intensity },
+                _ => return Err(format!("unknown light type {}", kind).into()),
+            });
+            None
+        }
+        "cast_shadows" => {
+            node.cast_shadows = parse_switch(args)?;
+            None
+        }
+        _ => return Err(format!("unknown node property {}", keyword).into()),
+    };
+
+    if let Some(transform) = transform {
+        node.transform = &transform * &node.transform;
+    }
+    Ok(())
+}
+
+fn expect_args(args: &[&str], count: usize) -> utils::Result<()> {
+    if args.len() != count {
+        return Err(format!("expected {} arguments, got {}", count, args.len()).into());
+    }
+    Ok(())
+}
+
+fn parse_floats<const N: usize>(args: &[&str]) -> utils::Result<[f32; N]> {
+    expect_args(args, N)?;
+    let mut floats = [0f32; N];
+    for (float, arg) in floats.iter_mut().zip(args) {
+        *float = arg
+            .parse()
+            .map_err(|_| format!("{} is not a number", arg))?;
+    }
+    Ok(floats)
+}
+
+fn parse_resolution(args: &[&str]) -> utils::Result<[u32; 2]> {
+    expect_args(args, 2)?;
+    let mut resolution = [0u32; 2];
+    for (size, arg) in resolution.iter_mut().zip(args) {
+        *size = match arg.parse() {
+            Ok(size) if size > 0 => size,
+            _ => return Err(format!("{} is not a valid size", arg).into()),
+        };
+    }
+    Ok(resolution)
+}
+
+fn parse_switch(args: &[&str]) -> utils::Result<bool> {
+    expect_args(args, 1)?;
+    match args[0] {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got {}", args[0]).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(text: &str) -> String {
+        match parse(text, Path::new("")) {
+            Ok(_) => panic!("parsed {:?}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn minimal_scene() {
+        let text = "
+            # a camera looking at a light
+            settings
+                resolution 320 240     # 4:3
+                shading flat
+                antialiasing off
+
+            node camera
+                look_at 0 0 3  0 0 0  0 1 0
+                camera pinhole 3
+
+            node light
+                parent camera
+                translate 1 2 3
+                light directional 0.5
+                camera perspective 60 1 0.1 10
+                cast_shadows off
+        ";
+        let scene_file = parse(text, Path::new("")).unwrap();
+
+        let settings = &scene_file.settings;
+        assert_eq!((settings.width, settings.height), (320, 240));
+        assert_eq!(settings.shading, Shading::Flat);
+        assert!(!settings.antialiasing);
+        assert!(settings.ambient_occlusion);
+        assert_eq!(settings.shadows, Shadows::Map);
+        assert!(scene_file.animation.is_none());
+
+        let scene = &scene_file.scene;
+        assert_eq!(scene.get_nnodes(), 2);
+        assert_eq!(scene_file.get_camera().unwrap(), 0);
+        assert_eq!(scene_file.get_shadow_light(), Some(1));
+        assert_eq!(
+            scene.get_node(0).camera,
+            Some(Camera::Pinhole { focal_length: 3f32 })
+        );
+
+        let light = scene.get_node(1);
+        assert_eq!(light.get_parent(), Some(0));
+        assert_eq!(light.light, Some(Light::Directional { intensity: 0.5 }));
+        assert!(!light.cast_shadows);
+        assert_eq!(
+            [0, 1, 2].map(|row| light.transform[row][3]),
+            [1f32, 2f32, 3f32]
+        );
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for (text, error) in [
+            ("frobnicate", "line 1: frobnicate outside of a block"),
+            (
+                "settings\nfrobnicate 1",
+                "line 2: unknown setting frobnicate",
+            ),
+            ("settings\nshadows soft", "line 2: unknown shadows soft"),
+            ("settings\nshading toon", "line 2: unknown shading toon"),
+            (
+                "animation\nfrobnicate",
+                "line 2: unknown animation property frobnicate",
+            ),
+            (
+                "material m\nfrobnicate",
+                "line 2: unknown material property frobnicate",
+            ),
+            ("material m\ncull both", "line 2: unknown cull mode both"),
+            (
+                "node n\nfrobnicate",
+                "line 2: unknown node property frobnicate",
+            ),
+            (
+                "node n\ncamera fisheye 1",
+                "line 2: unknown camera type fisheye",
+            ),
+            ("node n\nlight area 1", "line 2: unknown light type area"),
+            ("node n\nparent m", "line 2: no node named m"),
+            ("node n\ndraw mesh material", "line 2: no mesh named mesh"),
+        ] {
+            assert_eq!(parse_error(text), error, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        for (text, error) in [
+            // comments and blank lines are counted
+            (
+                "# resolution\n\nsettings\n    resolution 100 x\n",
+                "line 4: x is not a valid size",
+            ),
+            (
+                "settings\n\nresolution 0 100",
+                "line 3: 0 is not a valid size",
+            ),
+            (
+                "settings\nambient_occlusion yes",
+                "line 2: expected on or off, got yes",
+            ),
+            ("settings extra", "line 1: expected 0 arguments, got 1"),
+            (
+                "node n\ntranslate 1 2",
+                "line 2: expected 3 arguments, got 2",
+            ),
+            (
+                "node n\nrotate 0 1 0 ninety",
+                "line 2: ninety is not a number",
+            ),
+            ("node n\nnode n", "line 2: duplicate node n"),
+            ("material m\nmaterial m", "line 2: duplicate material m"),
+            (
+                "animation\nframes 0",
+                "line 2: 0 is not a valid frame count",
+            ),
+            // blocks checked when they end point at their first line
+            (
+                "settings\nanimation\n    frames 10\nnode n",
+                "line 2: animation has no turntable or keys",
+            ),
+            (
+                "animation\n    turntable 0 0 0  0 1 0\n",
+                "line 1: animation has no frame count",
+            ),
+            (
+                "animation\nframes 2\nkey 0  0 0 1  0 0 0  0 1 0",
+                "line 1: animation needs at least two keys",
+            ),
+        ] {
+            assert_eq!(parse_error(text), error, "{:?}", text);
+        }
+    }
+}
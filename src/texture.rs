@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use crate::{math::vector::Vec2f32, utils};
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -48,6 +50,10 @@ impl Texture {
         Ok(self.data[(x + y * self.width) as usize])
     }
 
+    pub fn clear(&mut self, color: TextureColor) {
+        self.data.fill(color);
+    }
+
     pub fn flip_horizontally(&mut self) {
         let half_width = self.width >> 1;
 
@@ -83,4 +89,54 @@ impl Texture {
     }
 }
 
+/// Reads a texture in the format given by the extension of `path`.
+pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_ref().and_then(|extension| extension.to_str()) {
+        Some("tga") => tga::read_from_file(path),
+        Some("bmp") => bmp::read_from_file(path),
+        Some("png") => png::read_from_file(path),
+        Some("pgm" | "ppm" | "pnm" | "pam") => netpbm::read_from_file(path),
+        _ => Err("unknown texture format".into()),
+    }
+}
+
+pub mod bmp;
+pub mod netpbm;
+pub mod png;
 pub mod tga;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // texture with every channel of every pixel different
+    pub fn pattern(width: u32, height: u32) -> Texture {
+        let mut texture = Texture::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let color = TextureColor {
+                    r: (x * 37 + y * 11) as u8,
+                    g: (x * 5 + y * 71 + 3) as u8,
+                    b: (x * y * 13 + 100) as u8,
+                    a: (x * 17 + y * 29 + 50) as u8,
+                };
+                texture.set_color(x, y, color).unwrap();
+            }
+        }
+        texture
+    }
+
+    pub fn get_colors(texture: &Texture) -> Vec<[u8; 4]> {
+        let mut colors = vec![];
+        for y in 0..texture.get_height() {
+            for x in 0..texture.get_width() {
+                let color = texture.get_color(x, y).unwrap();
+                colors.push([color.r, color.g, color.b, color.a]);
+            }
+        }
+        colors
+    }
+}
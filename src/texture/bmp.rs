@@ -0,0 +1,551 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::{Texture, TextureColor};
+use crate::utils;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const V4_HEADER_SIZE: usize = 108;
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+// largest RLE image read, 8192 by 8192 pixels
+const MAX_RLE_PIXELS: u32 = 1 << 26;
+
+// color space of written V4 headers
+const LCS_SRGB: u32 = 0x7352_4742;
+
+fn get_u16(data: &[u8], offset: usize) -> utils::Result<u16> {
+    let bytes = data
+        .get(offset..offset + 2)
+        .ok_or("unexpected end of bmp file")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn get_u32(data: &[u8], offset: usize) -> utils::Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or("unexpected end of bmp file")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Extracts the bits of a color channel and scales them to 8 bits.
+#[derive(Debug, Clone, Copy)]
+struct Mask {
+    mask: u32,
+    shift: u32,
+    max: u32,
+}
+
+impl Mask {
+    fn new(mask: u32) -> Mask {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        let max = mask >> shift;
+        Mask { mask, shift, max }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mask == 0
+    }
+
+    fn get(&self, pixel: u32) -> u8 {
+        if self.is_empty() {
+            return 0;
+        }
+        let value = ((pixel & self.mask) >> self.shift) as u64;
+        ((value * 255 + self.max as u64 / 2) / self.max as u64) as u8
+    }
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    top_down: bool,
+    bit_count: u16,
+    compression: u32,
+    // red, green, blue and alpha masks of 16 and 32-bit pixels
+    masks: [Mask; 4],
+    palette: Vec<TextureColor>,
+}
+
+impl Header {
+    fn get_palette_color(&self, index: u8) -> utils::Result<TextureColor> {
+        Ok(*self
+            .palette
+            .get(index as usize)
+            .ok_or("bmp palette index out of range")?)
+    }
+
+    // texture row of a stored row, which are bottom-up unless the height is
+    // negative
+    fn get_y(&self, row: usize) -> u32 {
+        if self.top_down {
+            row as u32
+        } else {
+            self.height - 1 - row as u32
+        }
+    }
+}
+
+fn parse_header(data: &[u8]) -> utils::Result<Header> {
+    if data.get(..2) != Some(b"BM") {
+        return Err("not a bmp file".into());
+    }
+    let header_size = get_u32(data, FILE_HEADER_SIZE)? as usize;
+    if !matches!(header_size, 40 | 52 | 56 | 108 | 124) {
+        return Err("unsupported bmp header".into());
+    }
+    let header = data
+        .get(FILE_HEADER_SIZE..FILE_HEADER_SIZE + header_size)
+        .ok_or("unexpected end of bmp file")?;
+
+    let width = get_u32(header, 4)? as i32;
+    let height = get_u32(header, 8)? as i32;
+    let bit_count = get_u16(header, 14)?;
+    let compression = get_u32(header, 16)?;
+    let colors_used = get_u32(header, 32)?;
+    if width <= 0 || height == 0 {
+        return Err("empty bmp image".into());
+    }
+
+    let valid = match compression {
+        BI_RGB => matches!(bit_count, 1 | 4 | 8 | 16 | 24 | 32),
+        BI_RLE8 => bit_count == 8 && height > 0,
+        BI_RLE4 => bit_count == 4 && height > 0,
+        BI_BITFIELDS | BI_ALPHABITFIELDS => matches!(bit_count, 16 | 32),
+        _ => false,
+    };
+    if !valid {
+        return Err("unsupported bmp compression or bit count".into());
+    }
+
+    // the masks follow a plain info header, and are part of the larger ones
+    let mut offset = FILE_HEADER_SIZE + header_size;
+    let masks = match compression {
+        BI_BITFIELDS | BI_ALPHABITFIELDS => {
+            let (source, start, count) = if header_size == INFO_HEADER_SIZE {
+                let count = if compression == BI_ALPHABITFIELDS {
+                    4
+                } else {
+                    3
+                };
+                offset += count * 4;
+                (data, FILE_HEADER_SIZE + INFO_HEADER_SIZE, count)
+            } else {
+                (
+                    header,
+                    INFO_HEADER_SIZE,
+                    if header_size > 52 { 4 } else { 3 },
+                )
+            };
+            let mut masks = [Mask::new(0); 4];
+            for (i, mask) in masks.iter_mut().enumerate().take(count) {
+                *mask = Mask::new(get_u32(source, start + i * 4)?);
+            }
+            masks
+        }
+        _ => match bit_count {
+            16 => [0x7c00, 0x03e0, 0x001f, 0].map(Mask::new),
+            _ => [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0].map(Mask::new),
+        },
+    };
+
+    let mut palette = vec![];
+    if bit_count <= 8 {
+        let count = match colors_used {
+            0 => 1 << bit_count,
+            count => count.min(1 << bit_count) as usize,
+        };
+        for i in 0..count {
+            let bgr = data
+                .get(offset + i * 4..offset + i * 4 + 3)
+                .ok_or("unexpected end of bmp palette")?;
+            palette.push(TextureColor {
+                r: bgr[2],
+                g: bgr[1],
+                b: bgr[0],
+                a: 255,
+            });
+        }
+    }
+
+    Ok(Header {
+        width: width as u32,
+        height: height.unsigned_abs(),
+        top_down: height < 0,
+        bit_count,
+        compression,
+        masks,
+        palette,
+    })
+}
+
+// Writes the palette colors of an RLE8 or RLE4 image to `texture`, leaving
+// the pixels skipped by deltas or an early end untouched.
+fn decode_rle(data: &[u8], header: &Header, texture: &mut Texture) -> utils::Result<()> {
+    let four_bits = header.compression == BI_RLE4;
+    let (width, height) = (header.width as usize, header.height as usize);
+    let (mut x, mut y) = (0usize, 0usize);
+    let mut position = 0;
+    let mut next = || -> utils::Result<u8> {
+        let byte = *data.get(position).ok_or("unexpected end of bmp rle data")?;
+        position += 1;
+        Ok(byte)
+    };
+    let mut put = |x: &mut usize, y: usize, index: u8| -> utils::Result<()> {
+        if *x < width && y < height {
+            texture.set_color(*x as u32, header.get_y(y), header.get_palette_color(index)?)?;
+        }
+        *x += 1;
+        Ok(())
+    };
+
+    while y < height {
+        let (count, value) = (next()?, next()?);
+        if count > 0 {
+            for i in 0..count {
+                let index = match (four_bits, i % 2) {
+                    (false, _) => value,
+                    (true, 0) => value >> 4,
+                    (true, _) => value & 0x0f,
+                };
+                put(&mut x, y, index)?;
+            }
+            continue;
+        }
+        match value {
+            // end of line
+            0 => {
+                x = 0;
+                y += 1;
+            }
+            // end of bitmap
+            1 => break,
+            2 => {
+                x += next()? as usize;
+                y += next()? as usize;
+            }
+            // absolute run, padded to a 16-bit boundary
+            length => {
+                let bytes = if four_bits {
+                    (length as usize).div_ceil(2)
+                } else {
+                    length as usize
+                };
+                let mut run = vec![];
+                for _ in 0..bytes {
+                    run.push(next()?);
+                }
+                if bytes % 2 == 1 {
+                    next()?;
+                }
+                for i in 0..length as usize {
+                    let index = if four_bits {
+                        (run[i / 2] >> (4 - 4 * (i % 2))) & 0x0f
+                    } else {
+                        run[i]
+                    };
+                    put(&mut x, y, index)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a BMP with an info, V4 or V5 header, with the top row of the image
+/// as row 0, like `tga::read_from_file`. Pixels skipped by RLE deltas are
+/// transparent black.
+pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
+    decode(&fs::read(path)?)
+}
+
+pub fn decode(data: &[u8]) -> utils::Result<Texture> {
+    let header = parse_header(data)?;
+    let pixels = data
+        .get(get_u32(data, 10)? as usize..)
+        .ok_or("unexpected end of bmp file")?;
+    let (width, height) = (header.width, header.height);
+
+    if header.compression == BI_RLE8 || header.compression == BI_RLE4 {
+        // deltas skip pixels, so the data does not bound the image size
+        if width
+            .checked_mul(height)
+            .is_none_or(|size| size > MAX_RLE_PIXELS)
+        {
+            return Err("bmp rle image too large".into());
+        }
+        let mut texture = Texture::new(width, height);
+        decode_rle(pixels, &header, &mut texture)?;
+        return Ok(texture);
+    }
+
+    let bit_count = header.bit_count as usize;
+    // rows are padded to 32 bits
+    let stride = (width as usize * bit_count).div_ceil(32) * 4;
+    if stride
+        .checked_mul(height as usize)
+        .is_none_or(|size| size > pixels.len())
+    {
+        return Err("unexpected end of bmp pixel data".into());
+    }
+    let mut texture = Texture::new(width, height);
+    let [red, green, blue, alpha] = header.masks;
+    for row in 0..height as usize {
+        let bytes = &pixels[row * stride..(row + 1) * stride];
+        for x in 0..width as usize {
+            let color = match bit_count {
+                1 | 4 | 8 => {
+                    let bit = x * bit_count;
+                    let shift = 8 - bit_count - bit % 8;
+                    header.get_palette_color(
+                        (bytes[bit / 8] >> shift) & ((1u16 << bit_count) - 1) as u8,
+                    )?
+                }
+                24 => TextureColor {
+                    r: bytes[x * 3 + 2],
+                    g: bytes[x * 3 + 1],
+                    b: bytes[x * 3],
+                    a: 255,
+                },
+                _ => {
+                    let pixel = if bit_count == 16 {
+                        get_u16(bytes, x * 2)? as u32
+                    } else {
+                        get_u32(bytes, x * 4)?
+                    };
+                    TextureColor {
+                        r: red.get(pixel),
+                        g: green.get(pixel),
+                        b: blue.get(pixel),
+                        a: if alpha.is_empty() {
+                            255
+                        } else {
+                            alpha.get(pixel)
+                        },
+                    }
+                }
+            };
+            texture.set_color(x as u32, header.get_y(row), color)?;
+        }
+    }
+    Ok(texture)
+}
+
+/// Writes a bottom-up 24-bit BMP, or when keeping alpha a 32-bit one with a
+/// V4 header holding the channel masks, with row 0 of `texture` at the
+/// bottom, like `tga::write_to_file`.
+pub fn write_to_file(texture: &Texture, path: &Path, ignore_alpha: bool) -> utils::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&encode(texture, ignore_alpha))?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn encode(texture: &Texture, ignore_alpha: bool) -> Vec<u8> {
+    let (width, height) = (texture.get_width(), texture.get_height());
+    let (bit_count, header_size) = if ignore_alpha {
+        (24u16, INFO_HEADER_SIZE)
+    } else {
+        (32u16, V4_HEADER_SIZE)
+    };
+    let stride = (width as usize * bit_count as usize).div_ceil(32) * 4;
+    let pixels_offset = FILE_HEADER_SIZE + header_size;
+    let file_size = pixels_offset + stride * height as usize;
+
+    let mut output = Vec::with_capacity(file_size);
+    output.extend(b"BM");
+    output.extend((file_size as u32).to_le_bytes());
+    output.extend([0u8; 4]);
+    output.extend((pixels_offset as u32).to_le_bytes());
+
+    output.extend((header_size as u32).to_le_bytes());
+    output.extend(width.to_le_bytes());
+    output.extend(height.to_le_bytes());
+    output.extend(1u16.to_le_bytes());
+    output.extend(bit_count.to_le_bytes());
+    let compression = if ignore_alpha { BI_RGB } else { BI_BITFIELDS };
+    output.extend(compression.to_le_bytes());
+    output.extend(((stride * height as usize) as u32).to_le_bytes());
+    // 72 dpi, then no palette
+    output.extend(2835u32.to_le_bytes());
+    output.extend(2835u32.to_le_bytes());
+    output.extend([0u8; 8]);
+    if !ignore_alpha {
+        for mask in [0x00ff_0000u32, 0x0000_ff00, 0x0000_00ff, 0xff00_0000] {
+            output.extend(mask.to_le_bytes());
+        }
+        output.extend(LCS_SRGB.to_le_bytes());
+        // endpoints and gamma, unused for sRGB
+        output.extend([0u8; 48]);
+    }
+
+    for y in 0..height {
+        let start = output.len();
+        for x in 0..width {
+            let color = texture.get_color(x, y).unwrap();
+            output.extend([color.b, color.g, color.r]);
+            if !ignore_alpha {
+                output.push(color.a);
+            }
+        }
+        output.resize(start + stride, 0);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::tests::{get_colors, pattern};
+
+    const NONE: [u8; 4] = [0, 0, 0, 0];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const OTHER: [u8; 4] = [10, 20, 30, 255];
+    // OTHER, RED, GREEN and BLUE as BGR0
+    const PALETTE: [u8; 16] = [30, 20, 10, 0, 0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0, 0];
+
+    // BMP with an info header, followed by the palette or masks in `extra`
+    fn build(
+        width: i32,
+        height: i32,
+        bit_count: u16,
+        compression: u32,
+        extra: &[u8],
+        pixels: &[u8],
+    ) -> Vec<u8> {
+        let offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE + extra.len();
+        let colors_used = if bit_count <= 8 { extra.len() / 4 } else { 0 };
+        let mut data = b"BM".to_vec();
+        data.extend(((offset + pixels.len()) as u32).to_le_bytes());
+        data.extend([0; 4]);
+        data.extend((offset as u32).to_le_bytes());
+        data.extend((INFO_HEADER_SIZE as u32).to_le_bytes());
+        data.extend(width.to_le_bytes());
+        data.extend(height.to_le_bytes());
+        data.extend(1u16.to_le_bytes());
+        data.extend(bit_count.to_le_bytes());
+        data.extend(compression.to_le_bytes());
+        data.extend((pixels.len() as u32).to_le_bytes());
+        data.extend([0; 8]);
+        data.extend((colors_used as u32).to_le_bytes());
+        data.extend([0; 4]);
+        data.extend(extra);
+        data.extend(pixels);
+        data
+    }
+
+    fn rle8() -> Vec<u8> {
+        #[rustfmt::skip]
+        let pixels = [
+            // absolute run, padded, then a run and the end of the line
+            0, 3, 1, 2, 3, 0, 1, 0, 0, 0,
+            // a delta to the last pixel of the next line, and the end
+            1, 2, 0, 2, 2, 1, 1, 3, 0, 1,
+        ];
+        build(4, 3, 8, BI_RLE8, &PALETTE, &pixels)
+    }
+
+    fn rle4() -> Vec<u8> {
+        // a run alternating two indices, then an absolute run of three
+        let pixels = [3, 0x12, 0, 0, 0, 3, 0x31, 0x20, 0, 1];
+        build(3, 2, 4, BI_RLE4, &PALETTE, &pixels)
+    }
+
+    #[test]
+    fn round_trip() {
+        for (width, height) in [(1, 1), (13, 9), (2, 5)] {
+            let texture = pattern(width, height);
+            for ignore_alpha in [false, true] {
+                let mut decoded = decode(&encode(&texture, ignore_alpha)).unwrap();
+                decoded.flip_vertically();
+                let mut expected = get_colors(&texture);
+                if ignore_alpha {
+                    expected.iter_mut().for_each(|color| color[3] = 255);
+                }
+                assert_eq!(get_colors(&decoded), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn rle() {
+        assert_eq!(
+            get_colors(&decode(&rle8()).unwrap()),
+            [NONE, NONE, NONE, BLUE, GREEN, NONE, NONE, NONE, RED, GREEN, BLUE, OTHER]
+        );
+        assert_eq!(
+            get_colors(&decode(&rle4()).unwrap()),
+            [BLUE, RED, GREEN, RED, GREEN, RED]
+        );
+    }
+
+    #[test]
+    fn bitfields() {
+        let masks: Vec<u8> = [0xf800u32, 0x07e0, 0x001f]
+            .iter()
+            .flat_map(|mask| mask.to_le_bytes())
+            .collect();
+        let bmp = build(2, 1, 16, BI_BITFIELDS, &masks, &[0x00, 0xf8, 0xff, 0x07]);
+        assert_eq!(
+            get_colors(&decode(&bmp).unwrap()),
+            [RED, [0, 255, 255, 255]]
+        );
+
+        let masks: Vec<u8> = [0xffu32, 0xff00, 0xff_0000, 0xff00_0000]
+            .iter()
+            .flat_map(|mask| mask.to_le_bytes())
+            .collect();
+        let bmp = build(1, 1, 32, BI_ALPHABITFIELDS, &masks, &[1, 2, 3, 4]);
+        assert_eq!(get_colors(&decode(&bmp).unwrap()), [[1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn top_down() {
+        let pixels = [0, 0, 255, 0, 0, 255, 0, 0];
+        let bmp = build(1, -2, 24, BI_RGB, &[], &pixels);
+        assert_eq!(get_colors(&decode(&bmp).unwrap()), [RED, GREEN]);
+    }
+
+    #[test]
+    fn truncated() {
+        let texture = pattern(5, 3);
+        for bmp in [
+            encode(&texture, true),
+            encode(&texture, false),
+            rle8(),
+            rle4(),
+        ] {
+            for length in 0..bmp.len() {
+                assert!(decode(&bmp[..length]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let too_large = i32::MAX;
+        for bmp in [
+            build(too_large, too_large, 24, BI_RGB, &[], &[0; 16]),
+            build(0x10000, 0x10000, 8, BI_RLE8, &PALETTE, &[0, 1]),
+            build(4, -3, 8, BI_RLE8, &PALETTE, &[0, 1]),
+            build(0, 1, 24, BI_RGB, &[], &[0; 4]),
+            build(1, 1, 24, 4, &[], &[0; 4]),
+            build(1, 1, 8, BI_RGB, &PALETTE, &[4, 0, 0, 0]),
+            build(2, 1, 8, BI_RLE8, &PALETTE, &[2, 7, 0, 1]),
+        ] {
+            assert!(decode(&bmp).is_err());
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::{Texture, TextureColor};
+use crate::utils;
+
+// longest line of the plain formats
+const MAX_LINE_LENGTH: usize = 70;
+
+/// Netpbm flavours that can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// P2, grayscale as decimal text.
+    PlainPgm,
+    /// P3, RGB as decimal text.
+    PlainPpm,
+    /// P5, binary grayscale.
+    Pgm,
+    /// P6, binary RGB.
+    Ppm,
+    /// P7 with the RGB_ALPHA tuple type.
+    Pam,
+}
+
+impl Format {
+    fn get_magic(&self) -> &'static str {
+        match self {
+            Format::PlainPgm => "P2",
+            Format::PlainPpm => "P3",
+            Format::Pgm => "P5",
+            Format::Ppm => "P6",
+            Format::Pam => "P7",
+        }
+    }
+
+    fn get_channels(&self) -> usize {
+        match self {
+            Format::PlainPgm | Format::Pgm => 1,
+            Format::PlainPpm | Format::Ppm => 3,
+            Format::Pam => 4,
+        }
+    }
+
+    fn is_plain(&self) -> bool {
+        matches!(self, Format::PlainPgm | Format::PlainPpm)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&byte) = self.data.get(self.position) {
+            if byte == b'#' {
+                while self
+                    .data
+                    .get(self.position)
+                    .is_some_and(|&byte| byte != b'\n')
+                {
+                    self.position += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    // next whitespace separated token, skipping comments
+    fn token(&mut self) -> utils::Result<&'a str> {
+        self.skip_whitespace();
+        let start = self.position;
+        while self
+            .data
+            .get(self.position)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err("unexpected end of netpbm file".into());
+        }
+        std::str::from_utf8(&self.data[start..self.position])
+            .map_err(|_| "invalid netpbm header".into())
+    }
+
+    fn number(&mut self) -> utils::Result<u32> {
+        let token = self.token()?;
+        token
+            .parse()
+            .map_err(|_| format!("{} is not a valid number", token).into())
+    }
+
+    // rest of the current line, for the PAM header
+    fn line(&mut self) -> utils::Result<&'a str> {
+        let start = self.position;
+        while self
+            .data
+            .get(self.position)
+            .is_some_and(|&byte| byte != b'\n')
+        {
+            self.position += 1;
+        }
+        if self.position >= self.data.len() {
+            return Err("unexpected end of netpbm file".into());
+        }
+        let line = std::str::from_utf8(&self.data[start..self.position])
+            .map_err(|_| "invalid netpbm header")?;
+        self.position += 1;
+        Ok(line.trim())
+    }
+
+    fn binary_sample(&mut self, maxval: u32) -> utils::Result<u32> {
+        let size = if maxval < 256 { 1 } else { 2 };
+        let bytes = self
+            .data
+            .get(self.position..self.position + size)
+            .ok_or("unexpected end of netpbm file")?;
+        self.position += size;
+        Ok(bytes
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as u32))
+    }
+}
+
+// Image size, samples per pixel and largest sample value of the header.
+struct Header {
+    width: u32,
+    height: u32,
+    channels: usize,
+    maxval: u32,
+}
+
+fn read_pam_header(reader: &mut Reader) -> utils::Result<Header> {
+    let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+    let mut tuple_type = None;
+    loop {
+        reader.skip_whitespace();
+        let line = reader.line()?;
+        let (keyword, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let number = || -> utils::Result<Option<u32>> {
+            Ok(Some(value.trim().parse().map_err(|_| {
+                format!("{} is not a valid number", value.trim())
+            })?))
+        };
+        match keyword {
+            "WIDTH" => width = number()?,
+            "HEIGHT" => height = number()?,
+            "DEPTH" => depth = number()?,
+            "MAXVAL" => maxval = number()?,
+            "TUPLTYPE" => tuple_type = Some(value.trim()),
+            "ENDHDR" => break,
+            _ => return Err(format!("unknown pam header line {}", keyword).into()),
+        }
+    }
+
+    let depth = depth.ok_or("pam depth missing")?;
+    let expected_depth = match tuple_type {
+        Some("BLACKANDWHITE" | "GRAYSCALE") => Some(1),
+        Some("GRAYSCALE_ALPHA") => Some(2),
+        Some("RGB") => Some(3),
+        Some("RGB_ALPHA") => Some(4),
+        Some(tuple_type) => {
+            return Err(format!("unsupported pam tuple type {}", tuple_type).into());
+        }
+        None => None,
+    };
+    if !(1..=4).contains(&depth) || expected_depth.is_some_and(|expected| expected != depth) {
+        return Err("unsupported pam depth".into());
+    }
+    Ok(Header {
+        width: width.ok_or("pam width missing")?,
+        height: height.ok_or("pam height missing")?,
+        channels: depth as usize,
+        maxval: maxval.ok_or("pam maxval missing")?,
+    })
+}
+
+/// Reads a P2, P3, P5, P6 or P7 image with any maxval up to 65535, with the
+/// top row of the image as row 0, like `tga::read_from_file`.
+pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
+    decode(&fs::read(path)?)
+}
+
+pub fn decode(data: &[u8]) -> utils::Result<Texture> {
+    let mut reader = Reader { data, position: 0 };
+    let magic = reader.token()?;
+    let (header, plain) = match magic {
+        "P2" | "P3" | "P5" | "P6" => {
+            let header = Header {
+                width: reader.number()?,
+                height: reader.number()?,
+                channels: if magic == "P2" || magic == "P5" { 1 } else { 3 },
+                maxval: reader.number()?,
+            };
+            // a single whitespace character separates the header from binary samples
+            reader.position += 1;
+            (header, magic == "P2" || magic == "P3")
+        }
+        "P7" => {
+            reader.position += 1;
+            (read_pam_header(&mut reader)?, false)
+        }
+        _ => return Err("unsupported netpbm format".into()),
+    };
+
+    let Header {
+        width,
+        height,
+        channels,
+        maxval,
+    } = header;
+    if width == 0 || height == 0 {
+        return Err("empty netpbm image".into());
+    }
+    if maxval == 0 || maxval > 65535 {
+        return Err("invalid netpbm maxval".into());
+    }
+
+    // every plain sample takes at least one digit, checked before allocating
+    let sample_size = if plain || maxval < 256 { 1 } else { 2 };
+    let data_size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(channels * sample_size));
+    let remaining = data.len().saturating_sub(reader.position);
+    if width.checked_mul(height).is_none() || data_size.is_none_or(|size| size > remaining) {
+        return Err("unexpected end of netpbm file".into());
+    }
+
+    let mut texture = Texture::new(width, height);
+    let mut samples = [0u8; 4];
+    for y in 0..height {
+        for x in 0..width {
+            for sample in samples.iter_mut().take(channels) {
+                let value = if plain {
+                    reader.number()?
+                } else {
+                    reader.binary_sample(maxval)?
+                };
+                if value > maxval {
+                    return Err("netpbm sample above maxval".into());
+                }
+                *sample = ((value * 255 + maxval / 2) / maxval) as u8;
+            }
+            let color = match channels {
+                1 => TextureColor {
+                    r: samples[0],
+                    g: samples[0],
+                    b: samples[0],
+                    a: 255,
+                },
+                2 => TextureColor {
+                    r: samples[0],
+                    g: samples[0],
+                    b: samples[0],
+                    a: samples[1],
+                },
+                3 => TextureColor {
+                    r: samples[0],
+                    g: samples[1],
+                    b: samples[2],
+                    a: 255,
+                },
+                _ => TextureColor {
+                    r: samples[0],
+                    g: samples[1],
+                    b: samples[2],
+                    a: samples[3],
+                },
+            };
+            texture.set_color(x, y, color)?;
+        }
+    }
+    Ok(texture)
+}
+
+/// Writes `texture` with samples scaled to `maxval`, two bytes per sample in
+/// the binary formats above 255, and row 0 at the bottom, like
+/// `tga::write_to_file`. Grayscale formats store the luma of the colors.
+pub fn write_to_file(
+    texture: &Texture,
+    path: &Path,
+    format: Format,
+    maxval: u16,
+) -> utils::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&encode(texture, format, maxval)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn encode(texture: &Texture, format: Format, maxval: u16) -> utils::Result<Vec<u8>> {
+    if maxval == 0 {
+        return Err("invalid netpbm maxval".into());
+    }
+    let (width, height) = (texture.get_width(), texture.get_height());
+
+    let mut output = vec![];
+    if format == Format::Pam {
+        write!(
+            output,
+            "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL {}\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+            width, height, maxval
+        )?;
+    } else {
+        write!(
+            output,
+            "{}\n{} {}\n{}\n",
+            format.get_magic(),
+            width,
+            height,
+            maxval
+        )?;
+    }
+
+    let scale = |value: u8| (value as u32 * maxval as u32 + 127) / 255;
+    let mut line_length = 0;
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let color = texture.get_color(x, y)?;
+            let luma =
+                (299 * color.r as u32 + 587 * color.g as u32 + 114 * color.b as u32 + 500) / 1000;
+            let samples = match format.get_channels() {
+                1 => vec![luma as u8],
+                3 => vec![color.r, color.g, color.b],
+                _ => vec![color.r, color.g, color.b, color.a],
+            };
+
+            for sample in samples {
+                let value = scale(sample);
+                if format.is_plain() {
+                    let text = value.to_string();
+                    if line_length > 0 && line_length + 1 + text.len() > MAX_LINE_LENGTH {
+                        output.push(b'\n');
+                        line_length = 0;
+                    } else if line_length > 0 {
+                        output.push(b' ');
+                        line_length += 1;
+                    }
+                    output.extend(text.as_bytes());
+                    line_length += text.len();
+                } else if maxval < 256 {
+                    output.push(value as u8);
+                } else {
+                    output.extend((value as u16).to_be_bytes());
+                }
+            }
+        }
+    }
+    if format.is_plain() {
+        output.push(b'\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::tests::{get_colors, pattern};
+
+    const FORMATS: [Format; 5] = [
+        Format::PlainPgm,
+        Format::PlainPpm,
+        Format::Pgm,
+        Format::Ppm,
+        Format::Pam,
+    ];
+
+    // colors as stored by `format`, with samples scaled to `maxval` and back
+    fn get_expected(texture: &Texture, format: Format, maxval: u16) -> Vec<[u8; 4]> {
+        let maxval = maxval as u32;
+        let scale = |value: u8| {
+            let stored = (value as u32 * maxval + 127) / 255;
+            ((stored * 255 + maxval / 2) / maxval) as u8
+        };
+        get_colors(texture)
+            .into_iter()
+            .map(|[r, g, b, a]| {
+                let luma = ((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8;
+                match format.get_channels() {
+                    1 => [scale(luma), scale(luma), scale(luma), 255],
+                    3 => [scale(r), scale(g), scale(b), 255],
+                    _ => [scale(r), scale(g), scale(b), scale(a)],
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let texture = pattern(13, 9);
+        for format in FORMATS {
+            for maxval in [255, 65535, 1000, 15] {
+                let mut decoded = decode(&encode(&texture, format, maxval).unwrap()).unwrap();
+                decoded.flip_vertically();
+                assert_eq!(
+                    get_colors(&decoded),
+                    get_expected(&texture, format, maxval),
+                    "{:?} {}",
+                    format,
+                    maxval
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn two_byte_samples() {
+        let texture = decode(b"P5 2 1 1000\n\x03\xe8\x01\xf4").unwrap();
+        assert_eq!(
+            get_colors(&texture),
+            [[255, 255, 255, 255], [128, 128, 128, 255]]
+        );
+        let texture = decode(b"P3\n# comment\n1 1\n65535\n65535 0 32768\n").unwrap();
+        assert_eq!(get_colors(&texture), [[255, 0, 128, 255]]);
+    }
+
+    #[test]
+    fn pam_gray_alpha() {
+        let pam = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n\
+            \x10\x20\x30\x40";
+        assert_eq!(
+            get_colors(&decode(pam).unwrap()),
+            [[0x10, 0x10, 0x10, 0x20], [0x30, 0x30, 0x30, 0x40]]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let texture = pattern(5, 3);
+        for format in FORMATS {
+            for maxval in [255, 65535] {
+                let data = encode(&texture, format, maxval).unwrap();
+                // cutting the last plain sample short leaves a valid number
+                let end = if format.is_plain() {
+                    let samples = data.trim_ascii_end();
+                    samples.iter().rposition(u8::is_ascii_whitespace).unwrap() + 1
+                } else {
+                    data.len()
+                };
+                for length in 0..end {
+                    assert!(decode(&data[..length]).is_err(), "{:?} {}", format, length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for data in [
+            &b""[..],
+            b"P4 1 1\n\x00",
+            b"P6 0 1 255\n",
+            b"P5 1 1 0\n\x00",
+            b"P5 1 1 65536\n\x00\x00",
+            b"P5 1 1 100\n\x65",
+            b"P2 1 1 255\n-1\n",
+            b"P2 1 x 255\n0\n",
+            b"P6\n70000 70000\n255\n",
+            b"P6 4294967295 4294967295 65535\n\x00",
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\x00\x00\x00\x00",
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE",
+            b"P7\nWIDTH 1\nDEPTH 1\nMAXVAL 255\nENDHDR\n\x00",
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nCOLOR red\nENDHDR\n\x00",
+        ] {
+            assert!(decode(data).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::{Texture, TextureColor};
+use crate::utils;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// largest IDAT chunk written
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+// first pixel and spacing of the pixels of each Adam7 pass, as (x, y, dx, dy)
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorType {
+    Gray,
+    Rgb,
+    Palette,
+    GrayAlpha,
+    Rgba,
+}
+
+impl ColorType {
+    fn from_code(code: u8) -> utils::Result<ColorType> {
+        match code {
+            0 => Ok(ColorType::Gray),
+            2 => Ok(ColorType::Rgb),
+            3 => Ok(ColorType::Palette),
+            4 => Ok(ColorType::GrayAlpha),
+            6 => Ok(ColorType::Rgba),
+            _ => Err("invalid png color type".into()),
+        }
+    }
+
+    fn get_channels(&self) -> u32 {
+        match self {
+            ColorType::Gray | ColorType::Palette => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    fn is_valid_depth(&self, depth: u8) -> bool {
+        match self {
+            ColorType::Gray => matches!(depth, 1 | 2 | 4 | 8 | 16),
+            ColorType::Palette => matches!(depth, 1 | 2 | 4 | 8),
+            _ => matches!(depth, 8 | 16),
+        }
+    }
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: ColorType,
+    interlaced: bool,
+}
+
+// first pixel, spacing, width, height and filtered row size of every pass,
+// as (x, y, dx, dy, width, height, row bytes)
+type Pass = (u32, u32, u32, u32, usize, u32, usize);
+
+impl Header {
+    // Passes of the image and the size of the filtered data holding them,
+    // none if that size does not fit in memory.
+    fn get_passes(&self) -> Option<(Vec<Pass>, usize)> {
+        let bits_per_pixel = self.color_type.get_channels() as usize * self.bit_depth as usize;
+        let layout: &[(u32, u32, u32, u32)] = if self.interlaced {
+            &ADAM7
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        self.width.checked_mul(self.height)?;
+
+        let mut passes = vec![];
+        let mut size = 0usize;
+        for &(x0, y0, dx, dy) in layout {
+            if x0 >= self.width || y0 >= self.height {
+                continue;
+            }
+            let pass_width = (self.width - x0).div_ceil(dx) as usize;
+            let pass_height = (self.height - y0).div_ceil(dy);
+            let row_bytes = pass_width.checked_mul(bits_per_pixel)?.div_ceil(8);
+            size = (row_bytes + 1)
+                .checked_mul(pass_height as usize)?
+                .checked_add(size)?;
+            passes.push((x0, y0, dx, dy, pass_width, pass_height, row_bytes));
+        }
+        Some((passes, size))
+    }
+}
+
+fn parse_header(data: &[u8]) -> utils::Result<Header> {
+    if data.len() != 13 {
+        return Err("invalid png header".into());
+    }
+    let width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let (bit_depth, color_type) = (data[8], ColorType::from_code(data[9])?);
+    if width == 0 || height == 0 {
+        return Err("empty png image".into());
+    }
+    if !color_type.is_valid_depth(bit_depth) {
+        return Err("invalid png bit depth".into());
+    }
+    if data[10] != 0 || data[11] != 0 {
+        return Err("unsupported png compression or filter method".into());
+    }
+    let interlaced = match data[12] {
+        0 => false,
+        1 => true,
+        _ => return Err("invalid png interlace method".into()),
+    };
+    Ok(Header {
+        width,
+        height,
+        bit_depth,
+        color_type,
+        interlaced,
+    })
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let (a16, b16, c16) = (a as i16, b as i16, c as i16);
+    let p = a16 + b16 - c16;
+    let (pa, pb, pc) = ((p - a16).abs(), (p - b16).abs(), (p - c16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Reverses the filter of `row` in place, with `bpp` the bytes per complete
+// pixel, at least 1, and `previous` the unfiltered row above.
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], bpp: usize) -> utils::Result<()> {
+    match filter {
+        0 => {}
+        1 => {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        2 => {
+            for i in 0..row.len() {
+                row[i] = row[i].wrapping_add(previous[i]);
+            }
+        }
+        3 => {
+            for i in 0..row.len() {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                row[i] = row[i].wrapping_add(((left as u16 + previous[i] as u16) / 2) as u8);
+            }
+        }
+        4 => {
+            for i in 0..row.len() {
+                let (left, upper_left) = if i >= bpp {
+                    (row[i - bpp], previous[i - bpp])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth(left, previous[i], upper_left));
+            }
+        }
+        _ => return Err("invalid png filter type".into()),
+    }
+    Ok(())
+}
+
+// Sample `index` of a row of samples of `bit_depth` bits, packed most
+// significant bits first below 8 bits.
+fn get_sample(row: &[u8], index: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+        8 => row[index] as u16,
+        _ => {
+            let bit = index * bit_depth as usize;
+            let shift = 8 - bit_depth as usize - bit % 8;
+            ((row[bit / 8] >> shift) & ((1u8 << bit_depth) - 1)) as u16
+        }
+    }
+}
+
+// Decoded image data and the chunks describing how to read it.
+struct Image {
+    header: Header,
+    palette: Vec<TextureColor>,
+    // transparent gray or rgb sample values, for those color types
+    transparent: Option<[u16; 3]>,
+    data: Vec<u8>,
+}
+
+impl Image {
+    fn get_color(&self, row: &[u8], x: usize) -> utils::Result<TextureColor> {
+        let depth = self.header.bit_depth;
+        let channels = self.header.color_type.get_channels() as usize;
+        let sample = |channel: usize| get_sample(row, x * channels + channel, depth);
+        // to 8 bits, repeating the bits of smaller samples
+        let scale = |value: u16| -> u8 {
+            match depth {
+                16 => (value >> 8) as u8,
+                8 => value as u8,
+                _ => (value as u32 * 255 / ((1u32 << depth) - 1)) as u8,
+            }
+        };
+        let opaque = |key: [u16; 3]| match self.transparent {
+            Some(transparent) if transparent == key => 0u8,
+            _ => 255u8,
+        };
+
+        let color = match self.header.color_type {
+            ColorType::Gray => {
+                let value = sample(0);
+                let gray = scale(value);
+                TextureColor {
+                    r: gray,
+                    g: gray,
+                    b: gray,
+                    a: opaque([value, value, value]),
+                }
+            }
+            ColorType::GrayAlpha => {
+                let gray = scale(sample(0));
+                TextureColor {
+                    r: gray,
+                    g: gray,
+                    b: gray,
+                    a: scale(sample(1)),
+                }
+            }
+            ColorType::Rgb => {
+                let (r, g, b) = (sample(0), sample(1), sample(2));
+                TextureColor {
+                    r: scale(r),
+                    g: scale(g),
+                    b: scale(b),
+                    a: opaque([r, g, b]),
+                }
+            }
+            ColorType::Rgba => TextureColor {
+                r: scale(sample(0)),
+                g: scale(sample(1)),
+                b: scale(sample(2)),
+                a: scale(sample(3)),
+            },
+            ColorType::Palette => *self
+                .palette
+                .get(sample(0) as usize)
+                .ok_or("png palette index out of range")?,
+        };
+        Ok(color)
+    }
+
+    fn into_texture(self) -> utils::Result<Texture> {
+        let Header {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            ..
+        } = self.header;
+        let bits_per_pixel = color_type.get_channels() as usize * bit_depth as usize;
+        let bpp = bits_per_pixel.div_ceil(8);
+
+        let (passes, size) = self.header.get_passes().ok_or("png image too large")?;
+        if size > self.data.len() {
+            return Err("png image data too short".into());
+        }
+
+        let mut texture = Texture::new(width, height);
+        let mut offset = 0;
+        for (x0, y0, dx, dy, pass_width, pass_height, row_bytes) in passes {
+            let mut previous = vec![0u8; row_bytes];
+            for y in 0..pass_height {
+                let filter = self.data[offset];
+                let mut row = self.data[offset + 1..offset + 1 + row_bytes].to_vec();
+                offset += 1 + row_bytes;
+                unfilter(filter, &mut row, &previous, bpp)?;
+
+                for x in 0..pass_width {
+                    let color = self.get_color(&row, x)?;
+                    texture.set_color(x0 + x as u32 * dx, y0 + y * dy, color)?;
+                }
+                previous = row;
+            }
+        }
+        Ok(texture)
+    }
+}
+
+/// Reads a PNG of any standard color type and bit depth, interlaced or not,
+/// with the top row of the image as row 0, like `tga::read_from_file`.
+pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
+    decode(&fs::read(path)?)
+}
+
+pub fn decode(data: &[u8]) -> utils::Result<Texture> {
+    if data.len() < 8 || data[..8] != SIGNATURE {
+        return Err("not a png file".into());
+    }
+
+    let mut header = None;
+    let mut palette = vec![];
+    let mut transparent = None;
+    let mut image_data = vec![];
+    let mut position = 8;
+    loop {
+        let chunk_header = data
+            .get(position..position + 8)
+            .ok_or("unexpected end of png file")?;
+        let length = u32::from_be_bytes([
+            chunk_header[0],
+            chunk_header[1],
+            chunk_header[2],
+            chunk_header[3],
+        ]) as usize;
+        let kind: [u8; 4] = chunk_header[4..8].try_into().unwrap();
+        let chunk = data
+            .get(position + 4..position + 8 + length + 4)
+            .ok_or("unexpected end of png file")?;
+        let (typed_data, crc) = chunk.split_at(4 + length);
+        if zlib::crc32(0, typed_data) != u32::from_be_bytes(crc.try_into().unwrap()) {
+            return Err("png chunk checksum mismatch".into());
+        }
+        let chunk_data = &typed_data[4..];
+        position += 12 + length;
+
+        if &kind != b"IHDR" && header.is_none() {
+            return Err("png file does not start with a header".into());
+        }
+        match &kind {
+            b"IHDR" => header = Some(parse_header(chunk_data)?),
+            b"PLTE" => {
+                if chunk_data.len() % 3 != 0 || chunk_data.len() > 256 * 3 {
+                    return Err("invalid png palette".into());
+                }
+                palette = chunk_data
+                    .chunks(3)
+                    .map(|rgb| TextureColor {
+                        r: rgb[0],
+                        g: rgb[1],
+                        b: rgb[2],
+                        a: 255,
+                    })
+                    .collect();
+            }
+            b"tRNS" => {
+                let sample = |i: usize| -> utils::Result<u16> {
+                    let bytes = chunk_data
+                        .get(i * 2..i * 2 + 2)
+                        .ok_or("invalid png transparency")?;
+                    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+                };
+                match header.as_ref().unwrap().color_type {
+                    ColorType::Palette => {
+                        for (color, &alpha) in palette.iter_mut().zip(chunk_data) {
+                            color.a = alpha;
+                        }
+                    }
+                    ColorType::Gray => {
+                        let gray = sample(0)?;
+                        transparent = Some([gray, gray, gray]);
+                    }
+                    ColorType::Rgb => transparent = Some([sample(0)?, sample(1)?, sample(2)?]),
+                    _ => return Err("invalid png transparency".into()),
+                }
+            }
+            b"IDAT" => image_data.extend_from_slice(chunk_data),
+            b"IEND" => break,
+            _ => {
+                // ancillary chunks have a lowercase first letter
+                if kind[0].is_ascii_uppercase() {
+                    return Err("unsupported critical png chunk".into());
+                }
+            }
+        }
+    }
+
+    let header = header.unwrap();
+    if header.color_type == ColorType::Palette && palette.is_empty() {
+        return Err("png palette missing".into());
+    }
+    let (_, size) = header.get_passes().ok_or("png image too large")?;
+    Image {
+        header,
+        palette,
+        transparent,
+        data: zlib::decompress(&image_data, size)?,
+    }
+    .into_texture()
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> utils::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = zlib::crc32(zlib::crc32(0, kind), data);
+    writer.write_all(&crc.to_be_bytes())?;
+    Ok(())
+}
+
+// Filters `row` with every filter type, keeping the one whose output has the
+// smallest sum of absolute values as signed bytes.
+fn filter_row(row: &[u8], previous: &[u8], bpp: usize, output: &mut Vec<u8>) {
+    let mut best: Option<(u64, u8, Vec<u8>)> = None;
+    for filter in 0..5u8 {
+        let filtered: Vec<u8> = (0..row.len())
+            .map(|i| {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let upper_left = if i >= bpp { previous[i - bpp] } else { 0 };
+                let predictor = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => previous[i],
+                    3 => ((left as u16 + previous[i] as u16) / 2) as u8,
+                    _ => paeth(left, previous[i], upper_left),
+                };
+                row[i].wrapping_sub(predictor)
+            })
+            .collect();
+        let cost = filtered
+            .iter()
+            .map(|&byte| (byte as i8).unsigned_abs() as u64)
+            .sum();
+        if best
+            .as_ref()
+            .is_none_or(|(best_cost, _, _)| cost < *best_cost)
+        {
+            best = Some((cost, filter, filtered));
+        }
+    }
+    let (_, filter, filtered) = best.unwrap();
+    output.push(filter);
+    output.extend(filtered);
+}
+
+/// Writes an 8-bit RGBA, or RGB when ignoring alpha, PNG with row 0 of
+/// `texture` at the bottom, like `tga::write_to_file`.
+pub fn write_to_file(texture: &Texture, path: &Path, ignore_alpha: bool) -> utils::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&encode(texture, ignore_alpha)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn encode(texture: &Texture, ignore_alpha: bool) -> utils::Result<Vec<u8>> {
+    let (width, height) = (texture.get_width(), texture.get_height());
+    let channels = if ignore_alpha { 3 } else { 4 };
+
+    let mut header = vec![];
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    header.extend([8, if ignore_alpha { 2 } else { 6 }, 0, 0, 0]);
+
+    let row_bytes = width as usize * channels;
+    let mut filtered = Vec::with_capacity((row_bytes + 1) * height as usize);
+    let mut previous = vec![0u8; row_bytes];
+    for y in (0..height).rev() {
+        let row: Vec<u8> = (0..width)
+            .flat_map(|x| {
+                let color = texture.get_color(x, y).unwrap();
+                [color.r, color.g, color.b, color.a]
+                    .into_iter()
+                    .take(channels)
+            })
+            .collect();
+        filter_row(&row, &previous, channels, &mut filtered);
+        previous = row;
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header)?;
+    for data in zlib::compress(&filtered).chunks(MAX_CHUNK_SIZE) {
+        write_chunk(&mut png, b"IDAT", data)?;
+    }
+    write_chunk(&mut png, b"IEND", &[])?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::tests::{get_colors, pattern};
+
+    // 3x3, 2-bit palette with transparency, Adam7 interlaced
+    const PALETTE_INTERLACED: [u8; 113] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x03, 0x02, 0x03, 0x00, 0x00, 0x01, 0x5c,
+        0x41, 0x6d, 0xba, 0x00, 0x00, 0x00, 0x0c, 0x50, 0x4c, 0x54, 0x45, 0xff, 0x00, 0x00, 0x00,
+        0xff, 0x00, 0x00, 0x00, 0xff, 0x0a, 0x14, 0x1e, 0x22, 0x88, 0x29, 0x04, 0x00, 0x00, 0x00,
+        0x02, 0x74, 0x52, 0x4e, 0x53, 0xff, 0x80, 0x08, 0x0f, 0xb3, 0x6a, 0x00, 0x00, 0x00, 0x12,
+        0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x60, 0x68, 0x00, 0x42, 0x07, 0x86, 0x03,
+        0x0c, 0x35, 0x00, 0x0c, 0x08, 0x02, 0x7d, 0x67, 0xd5, 0x48, 0xd5, 0x00, 0x00, 0x00, 0x00,
+        0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+    // 2x2, 16-bit gray and alpha, second row with the up filter
+    const GRAY_ALPHA_16: [u8; 80] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x10, 0x04, 0x00, 0x00, 0x00, 0x88,
+        0x2f, 0x19, 0xec, 0x00, 0x00, 0x00, 0x17, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x10,
+        0x32, 0xf9, 0xff, 0x7f, 0xf5, 0xd9, 0x06, 0x06, 0x26, 0x46, 0x06, 0x30, 0x60, 0x04, 0x00,
+        0x39, 0x4d, 0x04, 0x41, 0xb3, 0x99, 0x7e, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e,
+        0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    #[test]
+    fn round_trip() {
+        for (width, height) in [(1, 1), (13, 9), (300, 2)] {
+            let texture = pattern(width, height);
+            for ignore_alpha in [false, true] {
+                let mut decoded = decode(&encode(&texture, ignore_alpha).unwrap()).unwrap();
+                decoded.flip_vertically();
+                let mut expected = get_colors(&texture);
+                if ignore_alpha {
+                    expected.iter_mut().for_each(|color| color[3] = 255);
+                }
+                assert_eq!(get_colors(&decoded), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_interlaced() {
+        let (red, green, blue) = ([255, 0, 0, 255], [0, 255, 0, 128], [0, 0, 255, 255]);
+        let other = [10, 20, 30, 255];
+        assert_eq!(
+            get_colors(&decode(&PALETTE_INTERLACED).unwrap()),
+            [red, green, blue, green, other, other, blue, other, red]
+        );
+    }
+
+    #[test]
+    fn gray_alpha_16() {
+        assert_eq!(
+            get_colors(&decode(&GRAY_ALPHA_16).unwrap()),
+            [
+                [0x12, 0x12, 0x12, 0xff],
+                [0xab, 0xab, 0xab, 0x80],
+                [0x13, 0x13, 0x13, 0xff],
+                [0xab, 0xab, 0xab, 0x80]
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_or_corrupt() {
+        let png = encode(&pattern(13, 9), false).unwrap();
+        for length in 0..png.len() {
+            assert!(decode(&png[..length]).is_err());
+        }
+        // every byte is covered by the signature or a checksum
+        for i in 0..png.len() {
+            let mut corrupt = png.clone();
+            corrupt[i] ^= 0x10;
+            assert!(decode(&corrupt).is_err());
+        }
+    }
+
+    #[test]
+    fn huge_header() {
+        let mut png = SIGNATURE.to_vec();
+        let mut header = vec![];
+        header.extend(u32::MAX.to_be_bytes());
+        header.extend(u32::MAX.to_be_bytes());
+        header.extend([8, 6, 0, 0, 0]);
+        write_chunk(&mut png, b"IHDR", &header).unwrap();
+        write_chunk(&mut png, b"IDAT", &zlib::compress(&[0; 1000])).unwrap();
+        write_chunk(&mut png, b"IEND", &[]).unwrap();
+        assert!(decode(&png).is_err());
+
+        // within memory, but larger than the data
+        let mut png = SIGNATURE.to_vec();
+        header[..8].copy_from_slice(&[0, 0, 0x40, 0, 0, 0, 0x40, 0]);
+        write_chunk(&mut png, b"IHDR", &header).unwrap();
+        write_chunk(&mut png, b"IDAT", &zlib::compress(&[0; 1000])).unwrap();
+        write_chunk(&mut png, b"IEND", &[]).unwrap();
+        assert!(decode(&png).is_err());
+    }
+}
+
+pub mod zlib;
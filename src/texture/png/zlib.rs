@@ -0,0 +1,582 @@
+use crate::utils;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// CRC-32 as used by PNG chunks and gzip, continuing from the `crc` of the
+/// data before, which is 0 at the start.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &byte in data {
+        c = CRC_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+/// Adler-32 checksum of zlib streams, continuing from the `adler` of the
+/// data before, which is 1 at the start.
+pub fn adler32(adler: u32, data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    // the sums cannot overflow within 5552 bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+// Base values and extra bits of the length and distance codes.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Order in which dynamic blocks give the code lengths of the code length code.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const MAX_BITS: usize = 15;
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> utils::Result<u32> {
+        while self.nbits < n {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or("unexpected end of deflate data")?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.nbits;
+            self.nbits += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.nbits -= n;
+        Ok(value)
+    }
+
+    // drops the bits left of the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.nbits = 0;
+    }
+}
+
+// Canonical Huffman code, as the number of codes of each length and the
+// symbols ordered by code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> utils::Result<Huffman> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // over-subscribed codes cannot be decoded, incomplete ones can
+        let mut left = 1i32;
+        for &count in counts.iter().skip(1) {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err("invalid huffman code".into());
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> utils::Result<u16> {
+        // codes are read a bit at a time, most significant bit first
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid huffman code".into())
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    (
+        Huffman::new(&lengths).unwrap(),
+        Huffman::new(&[5u8; 30]).unwrap(),
+    )
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> utils::Result<(Huffman, Huffman)> {
+    let nlengths = reader.bits(5)? as usize + 257;
+    let ndistances = reader.bits(5)? as usize + 1;
+    let ncode_lengths = reader.bits(4)? as usize + 4;
+    if nlengths > 286 || ndistances > 30 {
+        return Err("invalid dynamic block".into());
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in CODE_LENGTH_ORDER.iter().take(ncode_lengths) {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; nlengths + ndistances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i]
+                    .last()
+                    .ok_or("repeated code length without a previous one")?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("too many code lengths".into());
+        }
+        lengths[i..i + repeat].fill(length);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("dynamic block without an end code".into());
+    }
+
+    Ok((
+        Huffman::new(&lengths[..nlengths])?,
+        Huffman::new(&lengths[nlengths..])?,
+    ))
+}
+
+const OUTPUT_TOO_LARGE: &str = "deflate output larger than expected";
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> utils::Result<()> {
+    loop {
+        let symbol = lengths.decode(reader)? as usize;
+        match symbol {
+            0..=255 => {
+                if output.len() >= limit {
+                    return Err(OUTPUT_TOO_LARGE.into());
+                }
+                output.push(symbol as u8)
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let index = symbol - 257;
+                let length =
+                    LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(reader)? as usize;
+                if index >= 30 {
+                    return Err("invalid distance code".into());
+                }
+                let distance = DISTANCE_BASE[index] as usize
+                    + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+                if distance > output.len() {
+                    return Err("distance too far back".into());
+                }
+                if output.len() + length > limit {
+                    return Err(OUTPUT_TOO_LARGE.into());
+                }
+                // copies may overlap the bytes they produce
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+            _ => return Err("invalid length code".into()),
+        }
+    }
+}
+
+/// Decompresses raw DEFLATE data, returning the output and how many bytes of
+/// `data` were used. Fails as soon as the output would exceed `limit` bytes.
+pub fn inflate(data: &[u8], limit: usize) -> utils::Result<(Vec<u8>, usize)> {
+    let mut reader = BitReader {
+        data,
+        position: 0,
+        buffer: 0,
+        nbits: 0,
+    };
+    let mut output = vec![];
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data
+                    .get(reader.position..reader.position + 4)
+                    .ok_or("unexpected end of deflate data")?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                let inverse = u16::from_le_bytes([header[2], header[3]]);
+                if length != !inverse {
+                    return Err("corrupt stored block length".into());
+                }
+                let start = reader.position + 4;
+                let stored = data
+                    .get(start..start + length as usize)
+                    .ok_or("unexpected end of deflate data")?;
+                if output.len() + stored.len() > limit {
+                    return Err(OUTPUT_TOO_LARGE.into());
+                }
+                output.extend_from_slice(stored);
+                reader.position = start + length as usize;
+            }
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut output, limit, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, limit, &lengths, &distances)?;
+            }
+            _ => return Err("invalid deflate block type".into()),
+        }
+        if last {
+            break;
+        }
+    }
+
+    // whole bytes read ahead into the buffer are given back
+    Ok((output, reader.position - (reader.nbits / 8) as usize))
+}
+
+/// Decompresses a zlib stream of at most `limit` bytes of output, checking
+/// its header and checksum.
+pub fn decompress(data: &[u8], limit: usize) -> utils::Result<Vec<u8>> {
+    if data.len() < 6 {
+        return Err("zlib stream too short".into());
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+        return Err("invalid zlib header".into());
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported".into());
+    }
+
+    let (output, used) = inflate(&data[2..], limit)?;
+    let trailer = data
+        .get(2 + used..2 + used + 4)
+        .ok_or("zlib stream without a checksum")?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(1, &output) {
+        return Err("zlib checksum mismatch".into());
+    }
+    Ok(output)
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, n: u32) {
+        self.buffer |= (value as u64) << self.nbits;
+        self.nbits += n;
+        while self.nbits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+// fixed code of a literal or length symbol
+fn write_fixed_symbol(writer: &mut BitWriter, symbol: usize) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.code(0x30 + symbol, 8),
+        144..=255 => writer.code(0x190 + symbol - 144, 9),
+        256..=279 => writer.code(symbol - 256, 7),
+        _ => writer.code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let index = LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1;
+    write_fixed_symbol(writer, 257 + index);
+    writer.bits(
+        (length - LENGTH_BASE[index] as usize) as u32,
+        LENGTH_EXTRA[index] as u32,
+    );
+
+    let index = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+    writer.code(index as u32, 5);
+    writer.bits(
+        (distance - DISTANCE_BASE[index] as usize) as u32,
+        DISTANCE_EXTRA[index] as u32,
+    );
+}
+
+const WINDOW_SIZE: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// how many earlier positions with the same hash are tried for a match
+const MAX_CHAIN: usize = 64;
+
+/// Compresses `data` into a single DEFLATE block with the fixed Huffman
+/// codes, finding repeats through hash chains over the last 32 KiB.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        bytes: vec![],
+        buffer: 0,
+        nbits: 0,
+    };
+    // last block, fixed codes
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+
+    let hash = |position: usize| -> usize {
+        let value = (data[position] as u32) << 16
+            | (data[position + 1] as u32) << 8
+            | data[position + 2] as u32;
+        (value.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+    };
+    // most recent position of each hash, and the one before each position,
+    // offset by one so that 0 means none
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut previous = vec![0usize; data.len()];
+    let insert = |position: usize, head: &mut [usize], previous: &mut [usize]| {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(position);
+            previous[position] = head[h];
+            head[h] = position + 1;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let mut best = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - position);
+            let mut candidate = head[hash(position)];
+            let mut chain = 0;
+            while candidate != 0 && chain < MAX_CHAIN {
+                let start = candidate - 1;
+                if position - start > WINDOW_SIZE {
+                    break;
+                }
+                let length = data[start..start + max_length]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, position - start);
+                    if length == max_length {
+                        break;
+                    }
+                }
+                candidate = previous[start];
+                chain += 1;
+            }
+        }
+
+        let (length, distance) = best;
+        if length >= MIN_MATCH {
+            write_match(&mut writer, length, distance);
+            for i in position..position + length {
+                insert(i, &mut head, &mut previous);
+            }
+            position += length;
+        } else {
+            write_fixed_symbol(&mut writer, data[position] as usize);
+            insert(position, &mut head, &mut previous);
+            position += 1;
+        }
+    }
+
+    write_fixed_symbol(&mut writer, 256);
+    writer.finish()
+}
+
+/// Compresses `data` into a zlib stream.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x9c];
+    output.extend(deflate(data));
+    output.extend(adler32(1, data).to_be_bytes());
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // streams written by zlib at levels 0, 1 and 9
+    const STORED: [u8; 23] = [
+        0x78, 0x01, 0x01, 0x0c, 0x00, 0xf3, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x77,
+        0x6f, 0x72, 0x6c, 0x64, 0x1d, 0x54, 0x04, 0x89,
+    ];
+    const FIXED: [u8; 22] = [
+        0x78, 0x01, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa1, 0xca, 0xf3, 0x8b,
+        0x72, 0x52, 0x00, 0x7c, 0x90, 0x09, 0x49,
+    ];
+    const DYNAMIC: [u8; 84] = [
+        0x78, 0xda, 0x8d, 0xcb, 0xc9, 0x11, 0x80, 0x20, 0x10, 0x44, 0xd1, 0x54, 0x3a, 0x0f, 0xa3,
+        0x01, 0x65, 0x53, 0x60, 0xd8, 0x11, 0xa3, 0x77, 0xca, 0x08, 0x3c, 0x76, 0xfd, 0xd7, 0xcd,
+        0x2a, 0xe4, 0xee, 0xf6, 0x0b, 0xb2, 0xd0, 0x8c, 0xd0, 0x74, 0xe3, 0xec, 0x21, 0x55, 0xd0,
+        0x50, 0x05, 0x8d, 0xb3, 0x17, 0xcf, 0xc2, 0x41, 0x66, 0xfb, 0xd6, 0x6f, 0x9c, 0x04, 0xbb,
+        0xb0, 0x20, 0x19, 0x4d, 0xd7, 0x2c, 0xb4, 0x1b, 0x8a, 0xd3, 0xa3, 0x22, 0xbc, 0xcb, 0x9d,
+        0x0a, 0x7f, 0x4d, 0x7d, 0x01, 0xf9, 0xa7, 0x2f, 0x32,
+    ];
+    const DYNAMIC_TEXT: &[u8] = b"the quick brown fox jumps over the lazy dog; \
+        the quick brown fox jumps over the lazy dog; pack my box with five dozen liquor jugs";
+
+    fn block_type(stream: &[u8]) -> u8 {
+        (stream[2] >> 1) & 3
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+        assert_eq!(adler32(1, b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(1, &[]), 1);
+    }
+
+    #[test]
+    fn stored_block() {
+        assert_eq!(block_type(&STORED), 0);
+        assert_eq!(decompress(&STORED, 100).unwrap(), b"hello, world");
+    }
+
+    #[test]
+    fn fixed_block() {
+        assert_eq!(block_type(&FIXED), 1);
+        assert_eq!(
+            decompress(&FIXED, 100).unwrap(),
+            b"hello, hello, hello, world"
+        );
+    }
+
+    #[test]
+    fn dynamic_block() {
+        assert_eq!(block_type(&DYNAMIC), 2);
+        assert_eq!(decompress(&DYNAMIC, 1000).unwrap(), DYNAMIC_TEXT);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data: Vec<u8> = (0..5000u32).map(|i| (i * i / 7) as u8).collect();
+        data.extend([0u8; 1000]);
+        data.extend(DYNAMIC_TEXT);
+        for input in [&[][..], b"a", &data] {
+            let stream = compress(input);
+            assert_eq!(decompress(&stream, input.len()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn output_limit() {
+        assert!(decompress(&STORED, 11).is_err());
+        assert!(decompress(&FIXED, 25).is_err());
+        assert!(decompress(&DYNAMIC, DYNAMIC_TEXT.len() - 1).is_err());
+        assert!(decompress(&compress(&[0u8; 100_000]), 99_999).is_err());
+    }
+
+    #[test]
+    fn truncated_or_corrupt() {
+        // padding bits are ignored, any other change is caught
+        for stream in [&STORED[..], &FIXED, &DYNAMIC] {
+            let expected = decompress(stream, 1000).unwrap();
+            for length in 0..stream.len() {
+                assert!(decompress(&stream[..length], 1000).is_err());
+            }
+            for i in 0..stream.len() {
+                for bit in 0..8 {
+                    let mut corrupt = stream.to_vec();
+                    corrupt[i] ^= 1 << bit;
+                    let output = decompress(&corrupt, 1000);
+                    assert!(output.is_err() || output.unwrap() == expected);
+                }
+            }
+        }
+    }
+}
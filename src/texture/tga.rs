@@ -24,7 +24,7 @@ struct TgaHeader {
     image_descriptor: u8,
 }
 
-fn set_image_data(texture: &mut Texture, raw_data: &Vec<u8>, pixel_depth: u32) {
+fn set_image_data(texture: &mut Texture, raw_data: &[u8], pixel_depth: u32) {
     match pixel_depth {
         8 => {
             for i in 0..texture.width {
@@ -75,7 +75,7 @@ fn set_image_data(texture: &mut Texture, raw_data: &Vec<u8>, pixel_depth: u32) {
     }
 }
 
-fn get_image_data(texture: &Texture, raw_data: &mut Vec<u8>, ignore_alpha: bool) {
+fn get_image_data(texture: &Texture, raw_data: &mut [u8], ignore_alpha: bool) {
     for i in 0..texture.width {
         for j in 0..texture.height {
             let index = ((i + j * texture.width) * 4) as usize;
@@ -161,7 +161,7 @@ fn rle_decode(
 }
 
 pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
-    let file = File::open(&path)?;
+    let file = File::open(path)?;
     let mut reader = BufReader::new(file);
 
     let tga_header: TgaHeader = unsafe { utils::read_raw_struct(&mut reader)? };
@@ -189,7 +189,6 @@ pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
         }
         10 => {
             rle_decode(&mut texture, &mut reader, pixel_depth)?;
-            ()
         }
         11 => {
             rle_decode(&mut texture, &mut reader, pixel_depth)?;
@@ -206,15 +205,16 @@ pub fn read_from_file(path: &Path) -> utils::Result<Texture> {
         texture.flip_vertically();
     }
 
-    return Ok(texture);
+    Ok(texture)
 }
 
 pub fn write_to_file(texture: &Texture, path: &Path, ignore_alpha: bool) -> utils::Result<()> {
     let file = File::options()
         .write(true)
         .create(true)
+        .truncate(true)
         .append(false)
-        .open(&path)?;
+        .open(path)?;
     let mut writer = BufWriter::new(file);
 
     let tga_header = TgaHeader {
@@ -19,6 +19,10 @@ pub fn read_n_bytes<T: Read>(reader: &mut T, bytes_to_read: u64) -> Result<Vec<u
     }
 }
 
+/// # Safety
+///
+/// `T` must be a plain-old-data type for which every bit pattern read from
+/// `reader` is a valid value.
 pub unsafe fn read_raw_struct<R: Read, T: Sized>(reader: &mut R) -> Result<T> {
     let mut struct_raw = mem::MaybeUninit::uninit();
     let struct_raw_slice =
@@ -26,9 +30,13 @@ pub unsafe fn read_raw_struct<R: Read, T: Sized>(reader: &mut R) -> Result<T> {
 
     reader.read_exact(struct_raw_slice)?;
 
-    return Ok(struct_raw.assume_init());
+    Ok(struct_raw.assume_init())
 }
 
+/// # Safety
+///
+/// `T` must not contain padding bytes, which would otherwise be read
+/// uninitialized when serializing `data`.
 pub unsafe fn write_raw_struct<W: Write, T: Sized>(writer: &mut W, data: &T) -> Result<()> {
     let struct_raw_slice =
         slice::from_raw_parts((data as *const T) as *const u8, mem::size_of::<T>());
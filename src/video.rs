@@ -0,0 +1,12 @@
+use crate::{texture::Texture, utils};
+
+/// Destination of a sequence of rendered frames, such as a video file. Rows
+/// of the frames run from the bottom of the image to its top, as rendered.
+pub trait FrameSink {
+    fn write_frame(&mut self, frame: &Texture) -> utils::Result<()>;
+    /// Completes the output after the last frame.
+    fn finish(&mut self) -> utils::Result<()>;
+}
+
+pub mod gif;
+pub mod y4m;
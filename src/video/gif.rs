@@ -0,0 +1,635 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::FrameSink;
+use crate::{
+    texture::{Texture, TextureColor},
+    utils,
+};
+
+const MAX_COLORS: usize = 256;
+const MAX_CODE_SIZE: u32 = 12;
+
+/// Animated GIF89a of frames of a fixed size. Every frame gets its own
+/// palette of up to 256 colors, chosen by median cut, and the alpha channel
+/// is ignored.
+pub struct GifWriter<W: Write> {
+    writer: W,
+    width: u32,
+    height: u32,
+    // in hundredths of a second
+    delay: u16,
+    dithering: bool,
+    loop_count: Option<u16>,
+    header_written: bool,
+}
+
+impl GifWriter<BufWriter<File>> {
+    pub fn create(path: &Path, width: u32, height: u32) -> utils::Result<Self> {
+        let file = File::create(path)?;
+        GifWriter::new(BufWriter::new(file), width, height)
+    }
+}
+
+impl<W: Write> GifWriter<W> {
+    /// A GIF that loops forever, without dithering and with frames shown for
+    /// a tenth of a second.
+    pub fn new(writer: W, width: u32, height: u32) -> utils::Result<Self> {
+        if width == 0 || height == 0 || width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err("unsupported frame size".into());
+        }
+        Ok(GifWriter {
+            writer,
+            width,
+            height,
+            delay: 10,
+            dithering: false,
+            loop_count: Some(0),
+            header_written: false,
+        })
+    }
+
+    /// How long the following frames are shown, in hundredths of a second.
+    pub fn set_delay(&mut self, delay: u16) {
+        self.delay = delay
+    }
+
+    pub fn get_delay(&self) -> u16 {
+        self.delay
+    }
+
+    /// Whether the following frames are Floyd-Steinberg dithered to their
+    /// palette.
+    pub fn set_dithering(&mut self, dithering: bool) {
+        self.dithering = dithering
+    }
+
+    /// How many times viewers repeat the animation after showing it once, 0
+    /// meaning forever and `None` not at all. Only takes effect before the
+    /// first frame.
+    pub fn set_loop_count(&mut self, loop_count: Option<u16>) {
+        self.loop_count = loop_count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self) -> utils::Result<()> {
+        self.writer.write_all(b"GIF89a")?;
+        // logical screen without a global color table
+        self.writer.write_all(&(self.width as u16).to_le_bytes())?;
+        self.writer.write_all(&(self.height as u16).to_le_bytes())?;
+        self.writer.write_all(&[0, 0, 0])?;
+
+        if let Some(loop_count) = self.loop_count {
+            self.writer.write_all(&[0x21, 0xff, 11])?;
+            self.writer.write_all(b"NETSCAPE2.0")?;
+            self.writer.write_all(&[3, 1])?;
+            self.writer.write_all(&loop_count.to_le_bytes())?;
+            self.writer.write_all(&[0])?;
+        }
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> FrameSink for GifWriter<W> {
+    fn write_frame(&mut self, frame: &Texture) -> utils::Result<()> {
+        if frame.get_width() != self.width || frame.get_height() != self.height {
+            return Err("frame size does not match the animation".into());
+        }
+        if !self.header_written {
+            self.write_header()?;
+        }
+
+        // rows from the top of the image down
+        let pixels: Vec<TextureColor> = (0..self.height)
+            .rev()
+            .flat_map(|y| (0..self.width).map(move |x| frame.get_color(x, y).unwrap()))
+            .collect();
+        let palette = median_cut(&pixels, MAX_COLORS);
+        let indices = map_to_palette(&pixels, self.width as usize, &palette, self.dithering);
+
+        // color tables hold a power of two of at least 2 entries
+        let table_bits = (palette.len().max(2) as u32)
+            .next_power_of_two()
+            .trailing_zeros();
+
+        // graphic control extension, leaving each frame in place for the next
+        self.writer.write_all(&[0x21, 0xf9, 4, 0x04])?;
+        self.writer.write_all(&self.delay.to_le_bytes())?;
+        self.writer.write_all(&[0, 0])?;
+
+        // image descriptor with a local color table
+        self.writer.write_all(&[0x2c, 0, 0, 0, 0])?;
+        self.writer.write_all(&(self.width as u16).to_le_bytes())?;
+        self.writer.write_all(&(self.height as u16).to_le_bytes())?;
+        self.writer.write_all(&[0x80 | (table_bits - 1) as u8])?;
+        for i in 0..1usize << table_bits {
+            let color = palette.get(i).copied().unwrap_or_default();
+            self.writer.write_all(&[color.r, color.g, color.b])?;
+        }
+
+        let min_code_size = table_bits.max(2);
+        self.writer.write_all(&[min_code_size as u8])?;
+        let data = lzw_encode(&indices, min_code_size);
+        for block in data.chunks(255) {
+            self.writer.write_all(&[block.len() as u8])?;
+            self.writer.write_all(block)?;
+        }
+        self.writer.write_all(&[0])?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> utils::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+        }
+        self.writer.write_all(&[0x3b])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Box of colors, with how often each of them occurs.
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>,
+}
+
+impl ColorBox {
+    // the channel with the widest range, and that range
+    fn get_widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let values = self.colors.iter().map(|(color, _)| color[channel]);
+                let (min, max) = values.fold((u8::MAX, u8::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+                (channel, max - min)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap()
+    }
+
+    fn get_average(&self) -> TextureColor {
+        let mut sums = [0u64; 3];
+        let mut count = 0u64;
+        for (color, n) in self.colors.iter() {
+            for channel in 0..3 {
+                sums[channel] += color[channel] as u64 * *n as u64;
+            }
+            count += *n as u64;
+        }
+        let average = |sum: u64| ((sum + count / 2) / count) as u8;
+        TextureColor {
+            r: average(sums[0]),
+            g: average(sums[1]),
+            b: average(sums[2]),
+            a: 255,
+        }
+    }
+}
+
+/// Palette of at most `max_colors` colors for `pixels`, found by repeatedly
+/// splitting the box of colors with the widest range at the median of that
+/// range's channel.
+pub fn median_cut(pixels: &[TextureColor], max_colors: usize) -> Vec<TextureColor> {
+    let mut histogram: HashMap<[u8; 3], u32> = HashMap::new();
+    for pixel in pixels {
+        *histogram.entry([pixel.r, pixel.g, pixel.b]).or_default() += 1;
+    }
+    let mut colors: Vec<([u8; 3], u32)> = histogram.into_iter().collect();
+    // keeps the palette independent of the iteration order of the map
+    colors.sort_unstable();
+    if colors.len() <= max_colors {
+        return colors
+            .into_iter()
+            .map(|([r, g, b], _)| TextureColor { r, g, b, a: 255 })
+            .collect();
+    }
+
+    let mut boxes = vec![ColorBox { colors }];
+    while boxes.len() < max_colors {
+        let (index, (channel, range)) = boxes
+            .iter()
+            .map(ColorBox::get_widest_channel)
+            .enumerate()
+            .max_by_key(|&(_, (_, range))| range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+
+        let mut colors = boxes.swap_remove(index).colors;
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+        let total: u64 = colors.iter().map(|&(_, n)| n as u64).sum();
+        let mut seen = 0u64;
+        let mut split = colors
+            .iter()
+            .position(|&(_, n)| {
+                seen += n as u64;
+                seen * 2 >= total
+            })
+            .unwrap()
+            + 1;
+        // both halves keep at least one color
+        split = split.clamp(1, colors.len() - 1);
+        let upper = colors.split_off(split);
+        boxes.push(ColorBox { colors });
+        boxes.push(ColorBox { colors: upper });
+    }
+
+    boxes.iter().map(ColorBox::get_average).collect()
+}
+
+fn nearest_color(palette: &[TextureColor], color: [i32; 3]) -> usize {
+    palette
+        .iter()
+        .map(|entry| {
+            let dr = entry.r as i32 - color[0];
+            let dg = entry.g as i32 - color[1];
+            let db = entry.b as i32 - color[2];
+            dr * dr + dg * dg + db * db
+        })
+        .enumerate()
+        .min_by_key(|&(_, distance)| distance)
+        .unwrap()
+        .0
+}
+
+/// The palette index of every pixel, optionally diffusing the error of each
+/// pixel onto its neighbours with the Floyd-Steinberg weights.
+pub fn map_to_palette(
+    pixels: &[TextureColor],
+    width: usize,
+    palette: &[TextureColor],
+    dithering: bool,
+) -> Vec<u8> {
+    let mut cache: HashMap<[i32; 3], u8> = HashMap::new();
+    let mut lookup = |color: [i32; 3]| -> u8 {
+        *cache
+            .entry(color)
+            .or_insert_with(|| nearest_color(palette, color) as u8)
+    };
+
+    if !dithering {
+        return pixels
+            .iter()
+            .map(|pixel| lookup([pixel.r as i32, pixel.g as i32, pixel.b as i32]))
+            .collect();
+    }
+
+    let height = pixels.len() / width;
+    // errors carried to the current and the next row, in sixteenths
+    let mut errors = vec![[0i32; 3]; width + 2];
+    let mut next_errors = vec![[0i32; 3]; width + 2];
+    let mut indices = Vec::with_capacity(pixels.len());
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = pixels[x + y * width];
+            let mut color = [pixel.r as i32, pixel.g as i32, pixel.b as i32];
+            for channel in 0..3 {
+                color[channel] = (color[channel] + errors[x + 1][channel] / 16).clamp(0, 255);
+            }
+
+            let index = lookup(color);
+            indices.push(index);
+
+            let entry = palette[index as usize];
+            let chosen = [entry.r as i32, entry.g as i32, entry.b as i32];
+            for channel in 0..3 {
+                let error = color[channel] - chosen[channel];
+                errors[x + 2][channel] += error * 7;
+                next_errors[x][channel] += error * 3;
+                next_errors[x + 1][channel] += error * 5;
+                next_errors[x + 2][channel] += error;
+            }
+        }
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.fill([0; 3]);
+    }
+    indices
+}
+
+// Packs variable-width codes into bytes, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, width: u32) {
+        self.buffer |= (code as u32) << self.nbits;
+        self.nbits += width;
+        while self.nbits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Variable-length LZW compression of GIF image data, starting from codes of
+/// `min_code_size` + 1 bits.
+pub fn lzw_encode(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear_code = 1u16 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut output = BitWriter {
+        bytes: vec![],
+        buffer: 0,
+        nbits: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut code_size = min_code_size + 1;
+    let mut next_code = end_code + 1;
+    // whether no code was written since the last clear code
+    let mut cleared = true;
+
+    output.write(clear_code, code_size);
+    let mut iter = indices.iter();
+    let mut prefix = match iter.next() {
+        Some(&index) => index as u16,
+        None => {
+            output.write(end_code, code_size);
+            return output.finish();
+        }
+    };
+
+    for &index in iter {
+        if let Some(&code) = table.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+
+        output.write(prefix, code_size);
+        cleared = false;
+        if next_code < 1 << MAX_CODE_SIZE {
+            table.insert((prefix, index), next_code);
+            next_code += 1;
+            // decoders add their entries one code later, and widen the codes
+            // once they have used up the current width
+            if next_code > 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+        } else {
+            output.write(clear_code, code_size);
+            table.clear();
+            code_size = min_code_size + 1;
+            next_code = end_code + 1;
+            cleared = true;
+        }
+        prefix = index as u16;
+    }
+
+    output.write(prefix, code_size);
+    // the entry decoders add for the last code can widen the end code
+    if !cleared && next_code == 1 << code_size && code_size < MAX_CODE_SIZE {
+        code_size += 1;
+    }
+    output.write(end_code, code_size);
+    output.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LZW decoder of GIF image data, the inverse of `lzw_encode`
+    fn lzw_decode(data: &[u8], min_code_size: u32) -> Result<Vec<u8>, String> {
+        let clear_code = 1u16 << min_code_size;
+        let end_code = clear_code + 1;
+        let (mut buffer, mut nbits, mut position) = (0u32, 0u32, 0);
+        let mut code_size = min_code_size + 1;
+        let mut table: Vec<Vec<u8>> = vec![];
+        let mut previous: Option<Vec<u8>> = None;
+        let mut output = vec![];
+        loop {
+            while nbits < code_size {
+                let byte = *data.get(position).ok_or("unexpected end of lzw data")?;
+                buffer |= (byte as u32) << nbits;
+                nbits += 8;
+                position += 1;
+            }
+            let code = (buffer & ((1 << code_size) - 1)) as u16;
+            buffer >>= code_size;
+            nbits -= code_size;
+
+            if code == clear_code {
+                table = (0..clear_code).map(|i| vec![i as u8]).collect();
+                table.extend([vec![], vec![]]);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return Ok(output);
+            }
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) if code < clear_code || code > end_code => entry.clone(),
+                (None, Some(previous)) if code as usize == table.len() => {
+                    [&previous[..], &previous[..1]].concat()
+                }
+                _ => return Err(format!("invalid lzw code {}", code)),
+            };
+            if let Some(previous) = previous {
+                if table.len() < 1 << MAX_CODE_SIZE {
+                    table.push([&previous[..], &entry[..1]].concat());
+                    if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                        code_size += 1;
+                    }
+                }
+            }
+            output.extend(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    // palette indices of every frame of a GIF written by `GifWriter`
+    fn read_frames(data: &[u8]) -> Vec<(Vec<TextureColor>, Vec<u8>)> {
+        assert_eq!(&data[..6], b"GIF89a");
+        let mut position = 13;
+        let mut frames = vec![];
+        loop {
+            match data[position] {
+                0x21 => {
+                    // extension, skipping its sub-blocks
+                    position += 2;
+                    while data[position] != 0 {
+                        position += 1 + data[position] as usize;
+                    }
+                    position += 1;
+                }
+                0x2c => {
+                    let table_bits = (data[position + 9] & 0x07) as usize + 1;
+                    position += 10;
+                    let palette = data[position..position + 3 * (1 << table_bits)]
+                        .chunks(3)
+                        .map(|rgb| TextureColor {
+                            r: rgb[0],
+                            g: rgb[1],
+                            b: rgb[2],
+                            a: 255,
+                        })
+                        .collect();
+                    position += 3 * (1 << table_bits);
+                    let min_code_size = data[position] as u32;
+                    position += 1;
+                    let mut lzw = vec![];
+                    while data[position] != 0 {
+                        let length = data[position] as usize;
+                        lzw.extend(&data[position + 1..position + 1 + length]);
+                        position += 1 + length;
+                    }
+                    position += 1;
+                    frames.push((palette, lzw_decode(&lzw, min_code_size).unwrap()));
+                }
+                0x3b => return frames,
+                byte => panic!("unexpected gif block {:#x}", byte),
+            }
+        }
+    }
+
+    // pseudo-random bytes below `limit`
+    fn noise(length: usize, limit: u32, mut seed: u32) -> Vec<u8> {
+        (0..length)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((seed >> 16) % limit) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lzw_known() {
+        // sample image of the GIF specification walkthrough by Matthew Flickinger
+        let rows = [
+            "1111122222",
+            "1111122222",
+            "1111122222",
+            "1110000222",
+            "1110000222",
+            "2220000111",
+            "2220000111",
+            "2222211111",
+            "2222211111",
+            "2222211111",
+        ];
+        let indices: Vec<u8> = rows.concat().bytes().map(|byte| byte - b'0').collect();
+        let expected = [
+            0x8c, 0x2d, 0x99, 0x87, 0x2a, 0x1c, 0xdc, 0x33, 0xa0, 0x02, 0x75, 0xec, 0x95, 0xfa,
+            0xa8, 0xde, 0x60, 0x8c, 0x04, 0x91, 0x4c, 0x01,
+        ];
+        assert_eq!(lzw_encode(&indices, 2), expected);
+        assert_eq!(lzw_decode(&expected, 2).unwrap(), indices);
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        for min_code_size in 2..=8 {
+            let limit = 1 << min_code_size;
+            // enough codes to fill the table and clear it several times
+            let mut inputs = vec![vec![], vec![1], noise(50_000, limit, min_code_size)];
+            inputs.push((0..20_000).map(|i| (i / 7 % limit) as u8).collect());
+            inputs.push(vec![(limit - 1) as u8; 10_000]);
+            for input in inputs {
+                let data = lzw_encode(&input, min_code_size);
+                assert_eq!(lzw_decode(&data, min_code_size).unwrap(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn lzw_truncated() {
+        let input = noise(1000, 16, 1);
+        let data = lzw_encode(&input, 4);
+        for length in 0..data.len() {
+            assert!(lzw_decode(&data[..length], 4).is_err());
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (width, height) = (37, 11);
+        let mut frames = vec![];
+        for (colors, seed) in [(2, 1), (200, 2), (256, 3)] {
+            let indices = noise((width * height) as usize, colors, seed);
+            let mut frame = Texture::new(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    let index = indices[(x + y * width) as usize];
+                    let color = TextureColor {
+                        r: index,
+                        g: index.wrapping_mul(7),
+                        b: 255 - index,
+                        a: 255,
+                    };
+                    frame.set_color(x, y, color).unwrap();
+                }
+            }
+            frames.push(frame);
+        }
+
+        let mut writer = GifWriter::new(vec![], width, height).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.finish().unwrap();
+        let decoded = read_frames(&writer.into_inner());
+
+        // frames of at most 256 colors are kept exactly, top row first
+        assert_eq!(decoded.len(), frames.len());
+        for (frame, (palette, indices)) in frames.iter().zip(decoded) {
+            assert_eq!(indices.len(), (width * height) as usize);
+            for (i, &index) in indices.iter().enumerate() {
+                let (x, row) = (i as u32 % width, i as u32 / width);
+                let expected = frame.get_color(x, height - 1 - row).unwrap();
+                let color = palette[index as usize];
+                assert_eq!(
+                    (color.r, color.g, color.b),
+                    (expected.r, expected.g, expected.b)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn median_cut_limits_colors() {
+        let pixels: Vec<TextureColor> = noise(30_000, 256, 4)
+            .chunks(3)
+            .map(|rgb| TextureColor {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+                a: 255,
+            })
+            .collect();
+        for max_colors in [1, 2, 16, 256] {
+            let palette = median_cut(&pixels, max_colors);
+            assert_eq!(palette.len(), max_colors);
+            let indices = map_to_palette(&pixels, 100, &palette, true);
+            assert!(indices.iter().all(|&index| (index as usize) < max_colors));
+        }
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(GifWriter::new(vec![], 0, 10).is_err());
+        assert!(GifWriter::new(vec![], 10, 70_000).is_err());
+        let mut writer = GifWriter::new(vec![], 10, 10).unwrap();
+        assert!(writer.write_frame(&Texture::new(10, 11)).is_err());
+    }
+}
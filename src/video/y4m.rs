@@ -0,0 +1,259 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::FrameSink;
+use crate::{
+    texture::{Texture, TextureColor},
+    utils,
+};
+
+/// Resolution of the chroma planes relative to the luma plane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// Half the width and height.
+    #[default]
+    Yuv420,
+    /// Half the width.
+    Yuv422,
+    /// Full resolution.
+    Yuv444,
+    /// No chroma planes, for grayscale.
+    Mono,
+}
+
+impl ChromaSubsampling {
+    // chroma samples per luma sample, horizontally and vertically
+    fn get_factors(&self) -> (u32, u32) {
+        match self {
+            ChromaSubsampling::Yuv420 => (2, 2),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv444 | ChromaSubsampling::Mono => (1, 1),
+        }
+    }
+
+    fn get_tag(&self) -> &'static str {
+        match self {
+            // chroma sited between the luma samples it covers
+            ChromaSubsampling::Yuv420 => "420jpeg",
+            ChromaSubsampling::Yuv422 => "422",
+            ChromaSubsampling::Yuv444 => "444",
+            ChromaSubsampling::Mono => "mono",
+        }
+    }
+}
+
+/// BT.601 conversion to limited range Y'CbCr as floats, with Y' from 16 to
+/// 235 and Cb and Cr from 16 to 240.
+pub fn rgb_to_ycbcr(color: TextureColor) -> (f32, f32, f32) {
+    let (r, g, b) = (color.r as f32, color.g as f32, color.b as f32);
+    let y = 16f32 + (65.481 * r + 128.553 * g + 24.966 * b) / 255f32;
+    let cb = 128f32 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255f32;
+    let cr = 128f32 + (112.0 * r - 93.786 * g - 18.214 * b) / 255f32;
+    (y, cb, cr)
+}
+
+/// Uncompressed YUV4MPEG2 stream of frames of a fixed size, as read by most
+/// video tools. The alpha channel of the frames is ignored.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: u32,
+    height: u32,
+    chroma: ChromaSubsampling,
+    // planes of the frame being written, sized once and reused across frames
+    y_plane: Vec<u8>,
+    cb_plane: Vec<u8>,
+    cr_plane: Vec<u8>,
+}
+
+impl Y4mWriter<BufWriter<File>> {
+    pub fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        frame_rate: (u32, u32),
+        chroma: ChromaSubsampling,
+    ) -> utils::Result<Self> {
+        let file = File::create(path)?;
+        Y4mWriter::new(BufWriter::new(file), width, height, frame_rate, chroma)
+    }
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Writes the stream header. `frame_rate` is in frames per second, as a
+    /// fraction `(numerator, denominator)`.
+    pub fn new(
+        mut writer: W,
+        width: u32,
+        height: u32,
+        frame_rate: (u32, u32),
+        chroma: ChromaSubsampling,
+    ) -> utils::Result<Self> {
+        if width == 0 || height == 0 {
+            return Err("empty frame size".into());
+        }
+        if frame_rate.0 == 0 || frame_rate.1 == 0 {
+            return Err("invalid frame rate".into());
+        }
+
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C{} XCOLORRANGE=LIMITED",
+            width,
+            height,
+            frame_rate.0,
+            frame_rate.1,
+            chroma.get_tag()
+        )?;
+
+        let (factor_x, factor_y) = chroma.get_factors();
+        let chroma_size = if chroma == ChromaSubsampling::Mono {
+            0
+        } else {
+            width.div_ceil(factor_x) as usize * height.div_ceil(factor_y) as usize
+        };
+        Ok(Y4mWriter {
+            writer,
+            width,
+            height,
+            chroma,
+            y_plane: vec![0; width as usize * height as usize],
+            cb_plane: vec![0; chroma_size],
+            cr_plane: vec![0; chroma_size],
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn convert(&mut self, frame: &Texture) {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let (factor_x, factor_y) = self.chroma.get_factors();
+        let (factor_x, factor_y) = (factor_x as usize, factor_y as usize);
+        let chroma_width = width.div_ceil(factor_x);
+
+        // chroma samples average the block of pixels they cover, clipped at
+        // the right and bottom edges
+        for chroma_y in 0..height.div_ceil(factor_y) {
+            for chroma_x in 0..chroma_width {
+                let (mut cb_sum, mut cr_sum, mut count) = (0f32, 0f32, 0u32);
+                for y in chroma_y * factor_y..((chroma_y + 1) * factor_y).min(height) {
+                    // the planes start with the top row
+                    let row = height - 1 - y;
+                    for x in chroma_x * factor_x..((chroma_x + 1) * factor_x).min(width) {
+                        let color = frame.get_color(x as u32, row as u32).unwrap();
+                        let (luma, cb, cr) = rgb_to_ycbcr(color);
+                        self.y_plane[x + y * width] = luma.round() as u8;
+                        cb_sum += cb;
+                        cr_sum += cr;
+                        count += 1;
+                    }
+                }
+
+                if self.chroma != ChromaSubsampling::Mono {
+                    let index = chroma_x + chroma_y * chroma_width;
+                    self.cb_plane[index] = (cb_sum / count as f32).round() as u8;
+                    self.cr_plane[index] = (cr_sum / count as f32).round() as u8;
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> FrameSink for Y4mWriter<W> {
+    fn write_frame(&mut self, frame: &Texture) -> utils::Result<()> {
+        if frame.get_width() != self.width || frame.get_height() != self.height {
+            return Err("frame size does not match the stream".into());
+        }
+
+        self.convert(frame);
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&self.y_plane)?;
+        if self.chroma != ChromaSubsampling::Mono {
+            self.writer.write_all(&self.cb_plane)?;
+            self.writer.write_all(&self.cr_plane)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> utils::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // red top row over blue, in a 3 by 3 frame
+    fn frame() -> Texture {
+        let mut texture = Texture::new(3, 3);
+        for y in 0..3 {
+            for x in 0..3 {
+                let (r, b) = if y == 2 { (255, 0) } else { (0, 255) };
+                let color = TextureColor { r, g: 0, b, a: 0 };
+                texture.set_color(x, y, color).unwrap();
+            }
+        }
+        texture
+    }
+
+    fn write(chroma: ChromaSubsampling, frames: usize) -> Vec<u8> {
+        let mut writer = Y4mWriter::new(vec![], 3, 3, (30000, 1001), chroma).unwrap();
+        for _ in 0..frames {
+            writer.write_frame(&frame()).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn subsampling() {
+        let luma = [81, 81, 81, 41, 41, 41, 41, 41, 41];
+        for (chroma, cb, cr) in [
+            // the right and bottom blocks are clipped to the frame
+            (
+                ChromaSubsampling::Yuv420,
+                &[165, 165, 240, 240][..],
+                &[175, 175, 110, 110][..],
+            ),
+            (
+                ChromaSubsampling::Yuv422,
+                &[90, 90, 240, 240, 240, 240],
+                &[240, 240, 110, 110, 110, 110],
+            ),
+            (
+                ChromaSubsampling::Yuv444,
+                &[90, 90, 90, 240, 240, 240, 240, 240, 240],
+                &[240, 240, 240, 110, 110, 110, 110, 110, 110],
+            ),
+            (ChromaSubsampling::Mono, &[], &[]),
+        ] {
+            let header = format!(
+                "YUV4MPEG2 W3 H3 F30000:1001 Ip A1:1 C{} XCOLORRANGE=LIMITED\n",
+                chroma.get_tag()
+            );
+            let mut frame = b"FRAME\n".to_vec();
+            frame.extend(luma);
+            frame.extend(cb);
+            frame.extend(cr);
+
+            let mut expected = header.into_bytes();
+            expected.extend(&frame);
+            expected.extend(&frame);
+            assert_eq!(write(chroma, 2), expected, "{:?}", chroma);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Y4mWriter::new(vec![], 0, 3, (25, 1), Default::default()).is_err());
+        assert!(Y4mWriter::new(vec![], 3, 3, (25, 0), Default::default()).is_err());
+        let mut writer = Y4mWriter::new(vec![], 4, 3, (25, 1), Default::default()).unwrap();
+        assert!(writer.write_frame(&frame()).is_err());
+    }
+}